@@ -9,7 +9,8 @@ window.addEventListener("load", function () {
     const f = freq.toLocaleLowerCase();
     const ids = f.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
     const text = f.split(',').map(id => localeColour[locale][id] || id).join(', ');
-    document.getElementById('channel-title').innerHTML = ids + `<p class="txt freq-guide">${text}</p>`;;
+    const history = `<a class="txt" href="/history.html?freq=${encodeURIComponent(freq)}&lang=${locale}">${localeText[locale].historyLink}</a>`;
+    document.getElementById('channel-title').innerHTML = ids + `<p class="txt freq-guide">${text}</p>` + history;
 });
 
 function sortBy(sort_by){
@@ -65,7 +66,14 @@ async function fetchData() {
 
 window.onload = function () {
     fetchData();
-    setInterval(fetchData, 1000); // 1秒毎にfetchDataを実行
+    // この周波数が変更された時だけ取得し直す
+    const source = new EventSource('/api/events');
+    source.addEventListener('update', e => {
+        const events = JSON.parse(e.data);
+        if (events.some(event => event.id === freq && (event.type === 'fluid_frequency' || event.type === 'frequency_removed'))) fetchData();
+    });
+    source.addEventListener('resync', fetchData);
+    source.addEventListener('open', fetchData);
 };
 
 // Resizable table container
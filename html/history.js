@@ -0,0 +1,77 @@
+// 周波数を指定しなければ全周波数の合計
+const freq = new URLSearchParams(window.location.search).get('freq');
+const hours = Number(new URLSearchParams(window.location.search).get('hours') || 24);
+// ロケールによって表示するテキストを変更する
+window.addEventListener("load", function () {
+    document.getElementById('history-title').innerText = localeText[locale].historyTitle;
+    document.getElementById('item-stacks-title').innerText = localeText[locale].itemStacksChart;
+    document.getElementById('item-count-title').innerText = localeText[locale].itemCountChart;
+    document.getElementById('fluid-amount-title').innerText = localeText[locale].fluidAmountChart;
+    document.getElementById('energy-title').innerText = localeText[locale].energyChart;
+    if (freq) {
+        const f = freq.toLocaleLowerCase();
+        const ids = f.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
+        const text = f.split(',').map(id => localeColour[locale][id] || id).join(', ');
+        document.getElementById('channel-title').innerHTML = ids + `<p class="txt freq-guide">${text}</p>`;
+    }
+});
+
+function setHours(hours) {
+    const parms = new URLSearchParams(window.location.search);
+    parms.set("hours", hours);
+    window.location.href = window.location.pathname + "?" + parms;
+}
+
+function drawChart(canvasId, points, key) {
+    const canvas = document.getElementById(canvasId);
+    const ctx = canvas.getContext('2d');
+    ctx.clearRect(0, 0, canvas.width, canvas.height);
+    if (points.length == 0) return;
+    const left = 80;
+    const width = canvas.width - left;
+    const height = canvas.height - 20;
+    const start = points[0].time;
+    const end = Math.max(points[points.length - 1].time, start + 1);
+    const max = Math.max(1, ...points.map(p => p[key]));
+    // 軸と目盛り
+    ctx.fillStyle = '#888';
+    ctx.font = '12px sans-serif';
+    ctx.fillText(max.toLocaleString(), 0, 12);
+    ctx.fillText('0', 0, height);
+    ctx.fillText(new Date(start).toLocaleString(), left, canvas.height - 4);
+    const endText = new Date(end).toLocaleString();
+    ctx.fillText(endText, canvas.width - ctx.measureText(endText).width, canvas.height - 4);
+    ctx.strokeStyle = '#888';
+    ctx.beginPath();
+    ctx.moveTo(left, 0);
+    ctx.lineTo(left, height);
+    ctx.lineTo(canvas.width, height);
+    ctx.stroke();
+    // 値
+    ctx.strokeStyle = '#3a8';
+    ctx.beginPath();
+    points.forEach((p, i) => {
+        const x = left + (p.time - start) / (end - start) * width;
+        const y = height - p[key] / max * height;
+        if (i == 0) ctx.moveTo(x, y);
+        else ctx.lineTo(x, y);
+    });
+    ctx.stroke();
+}
+
+async function fetchHistory() {
+    const url = new URL('/api/history.json', window.location.origin);
+    if (freq) url.searchParams.set('frequency', freq);
+    url.searchParams.set('since', Date.now() - hours * 3600 * 1000);
+    const response = await fetch(url);
+    const points = await response.json();
+    drawChart('item-stacks-chart', points, 'item_stacks');
+    drawChart('item-count-chart', points, 'item_count');
+    drawChart('fluid-amount-chart', points, 'fluid_amount');
+    drawChart('energy-chart', points, 'energy');
+}
+
+window.onload = function () {
+    fetchHistory();
+    setInterval(fetchHistory, 60000); // 記録間隔に合わせて1分毎
+};
@@ -1,5 +1,12 @@
-let previousItemData = {item: [], fluid: [], energy: []};
-let previousEnergyTimestamp=null;
+// 周波数・クライアントごとの現在値 一覧の取得とプッシュ通知で更新する
+const state = {item: new Map(), fluid: new Map(), energy: new Map(), client: new Map()};
+// 差分表示用の変更前の値
+const previousSize = {item: new Map(), fluid: new Map()};
+// 全体の在庫に表示する件数
+const SUMMARY_TOP = 10;
+// 全体の在庫は全チャンネルを集計するので更新の間隔を空ける
+const SUMMARY_INTERVAL_MILLIS = 5000;
+let summaryTimer = null;
 // ロケールによって表示するテキストを変更する
 window.addEventListener("load", function () {
     document.getElementById('item-info-title').innerText = localeText[locale].itemInfoTitle;
@@ -12,134 +19,247 @@ window.addEventListener("load", function () {
     document.getElementById('energy-channel-header').innerText = localeText[locale].energyChannelHeader;
     document.getElementById('energy-type-header').innerText = localeText[locale].energyAmountHeader;
     document.getElementById('host-info-title').innerText = localeText[locale].clientHostName;
+    document.getElementById('search-box').placeholder = localeText[locale].globalSearchPlaceholder;
+    document.getElementById('search-lang').value = locale;
+    document.getElementById('summary-title').innerText = localeText[locale].summaryTitle;
+    document.getElementById('summary-item-header').innerText = localeText[locale].summaryItemHeader;
+    document.getElementById('summary-item-amount-header').innerText = localeText[locale].amountHeader;
+    document.getElementById('summary-fluid-header').innerText = localeText[locale].summaryFluidHeader;
+    document.getElementById('summary-fluid-amount-header').innerText = localeText[locale].amountHeader;
+    document.getElementById('host-name-header').innerText = localeText[locale].hostNameHeader;
+    document.getElementById('host-frequency-header').innerText = localeText[locale].hostFrequencyHeader;
+    document.getElementById('host-sync-header').innerText = localeText[locale].hostSyncHeader;
+    document.getElementById('host-traffic-header').innerText = localeText[locale].hostTrafficHeader;
+    document.getElementById('host-inserted-header').innerText = localeText[locale].hostInsertedHeader;
+    document.getElementById('host-taken-header').innerText = localeText[locale].hostTakenHeader;
+    document.getElementById('host-connected-header').innerText = localeText[locale].hostConnectedHeader;
+    document.getElementById('host-activity-header').innerText = localeText[locale].hostActivityHeader;
 });
-async function fetchItem() {
-    const response = await fetch('/api/list/item_frequency.json');
-    const data = await response.json();
-    const table = document.getElementById('item-list').getElementsByTagName('tbody')[0];
-
+function freqCell(cell, id, page) {
+    const ids = id.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
+    const text = id.split(',').map(id => localeColour[locale][id] || id).join(', ');
+    const link = `<a href="/${page}?freq=${id.toUpperCase()}&lang=${locale}">` + ids + '</a>';
+    cell.innerHTML = link + ' ' + `<span class="txt freq-guide">${text}</span>`;
+}
+function diffSpan(difference, unit) {
+    const differenceText = difference > 0 ? `+${difference.toLocaleString()}` : difference.toLocaleString();
+    return `<span class="diff-value ${difference > 0 ? 'add' : difference < 0 ? 'sub' : 'zero'}">${difference == 0 ? "±" : ""}${differenceText}${unit}</span>`;
+}
+function resizeTable(table, length) {
     // 行数を調整
-    while (table.rows.length < data.length) {
+    while (table.rows.length < length) {
         table.insertRow();
     }
-    while (table.rows.length > data.length) {
+    while (table.rows.length > length) {
         table.deleteRow(-1);
     }
-
+}
+function renderSizes(kind, tableId, page) {
+    const table = document.getElementById(tableId).getElementsByTagName('tbody')[0];
+    resizeTable(table, state[kind].size);
     // 行を上から書き換え
-    data.forEach((item, index) => {
+    [...state[kind]].forEach(([id, size], index) => {
         const row = table.rows[index];
-        let cell1 = row.cells[0];
-        let cell2 = row.cells[1];
-        if (!cell1) cell1 = row.insertCell(0);
-        if (!cell2) cell2 = row.insertCell(1);
-
-        const ids = item.id.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
-        const localeText = item.id.split(',').map(id => localeColour[locale][id] || id).join(', ');
-        cell1.innerHTML = `<a href="/items.html?freq=${item.id.toUpperCase()}&lang=${locale}">`+ids + '</a> ' + `<span class="txt freq-guide">${localeText}</span>`;
-
-        const previousItem = previousItemData.item[index] || {};
-        const difference = item.size - (previousItem.size || 0);
-        const differenceText = difference > 0 ? `+${difference}` : difference;
-        cell2.innerHTML = `${item.size.toLocaleString()} <span class="diff-value ${difference > 0 ? 'add' : difference < 0 ? 'sub' : 'zero'}">${difference==0?"±":""}${differenceText.toLocaleString()}</span>`;
+        const cell1 = row.cells[0] || row.insertCell(0);
+        const cell2 = row.cells[1] || row.insertCell(1);
+        freqCell(cell1, id, page);
+        const difference = size - (previousSize[kind].get(id) ?? size);
+        cell2.innerHTML = `${size.toLocaleString()} ` + diffSpan(difference, "");
         cell2.classList.add('right-align');
     });
-
-    // 現在のデータを保存
-    previousItemData.item = data;
 }
-
-async function fetchFluid() {
-    const response = await fetch('/api/list/fluid_frequency.json');
-    const data = await response.json();
-    const table = document.getElementById('fluid-list').getElementsByTagName('tbody')[0];
-
-    // 行数を調整
-    while (table.rows.length < data.length) {
-        table.insertRow();
-    }
-    while (table.rows.length > data.length) {
-        table.deleteRow(-1);
-    }
-
-    // 行を上から書き換え
-    data.forEach((item, index) => {
+function renderItem() {
+    renderSizes('item', 'item-list', 'items.html');
+}
+function renderFluid() {
+    renderSizes('fluid', 'fluid-list', 'fluids.html');
+}
+function renderEnergy() {
+    const table = document.getElementById('energy-list').getElementsByTagName('tbody')[0];
+    const data = [...state.energy].filter(([id, item]) => item.value != 0 || item.loss != 0);
+    resizeTable(table, data.length);
+    data.forEach(([id, item], index) => {
         const row = table.rows[index];
-        let cell1 = row.cells[0];
-        let cell2 = row.cells[1];
-        if (!cell1) cell1 = row.insertCell(0);
-        if (!cell2) cell2 = row.insertCell(1);
-
-        const ids = item.id.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
-        const localeText = item.id.split(',').map(id => localeColour[locale][id] || id).join(', ');
-        cell1.innerHTML = `<a href="/fluids.html?freq=${item.id.toUpperCase()}&lang=${locale}">`+ids + '</a> ' + `<span class="txt freq-guide">${localeText}</span>`;
-
-        const previousItem = previousItemData.fluid[index] || {};
-        const difference = item.size - (previousItem.size || 0);
-        const differenceText = difference > 0 ? `+${difference}` : difference;
-        cell2.innerHTML = `${item.size.toLocaleString()} <span class="diff-value ${difference > 0 ? 'add' : difference < 0 ? 'sub' : 'zero'}">${difference==0?"±":""}${differenceText.toLocaleString()}</span>`;
+        const cell1 = row.cells[0] || row.insertCell(0);
+        const cell2 = row.cells[1] || row.insertCell(1);
+        freqCell(cell1, id, 'history.html');
+        cell2.innerHTML = `${item.value.toLocaleString()} / ${item.capacity.toLocaleString()} ` + diffSpan(item.rate || 0, "RF/t") + (item.loss > 0 ? ` <span class="diff-value sub">-${item.loss.toLocaleString()}RF</span>` : "");
         cell2.classList.add('right-align');
     });
-
-    // 現在のデータを保存
-    previousItemData.fluid = data;
+}
+function formatBytes(bytes) {
+    const units = ['B', 'KiB', 'MiB', 'GiB'];
+    let i = 0;
+    while (bytes >= 1024 && i < units.length - 1) {
+        bytes /= 1024;
+        i++;
+    }
+    return `${i == 0 ? bytes : bytes.toFixed(1)}${units[i]}`;
+}
+function formatMoved(moved) {
+    return `${moved.items.toLocaleString()} / ${moved.fluid.toLocaleString()}mB / ${moved.energy.toLocaleString()}RF`;
+}
+function formatTime(millis) {
+    return millis ? new Date(millis).toLocaleString(locale) : "";
+}
+function renderClients() {
+    const table = document.getElementById('host-list').getElementsByTagName('tbody')[0];
+    resizeTable(table, state.client.size);
+    [...state.client.values()].forEach((item, index) => {
+        const row = table.rows[index];
+        while (row.cells.length < 8) row.insertCell();
+        const stats = item.stats;
+        row.cells[0].innerText = item.name;
+        // コマンドごとの回数はホスト名にカーソルを合わせると表示する
+        row.cells[0].title = stats ? Object.entries(stats.commands).map(([name, count]) => `${name}: ${count.toLocaleString()}`).join('\n') : "";
+        if (stats && stats.frequency) {
+            freqCell(row.cells[1], stats.frequency, 'items.html');
+        } else {
+            row.cells[1].innerHTML = "";
+        }
+        row.cells[2].innerText = item.sync + "ms";
+        // 直近の同期時間の統計
+        const sync = stats && stats.sync;
+        row.cells[2].title = sync && sync.count > 0 ? `min ${sync.min}ms / avg ${sync.avg}ms / p95 ${sync.p95}ms / max ${sync.max}ms\n${sync.per_minute.toFixed(1)}/min` : "";
+        row.classList.toggle('slow-sync', !!(sync && sync.slow));
+        row.cells[3].innerText = stats ? `${formatBytes(stats.bytes_in)} / ${formatBytes(stats.bytes_out)}` : "";
+        row.cells[4].innerText = stats ? formatMoved(stats.inserted) : "";
+        row.cells[5].innerText = stats ? formatMoved(stats.taken) : "";
+        row.cells[6].innerText = stats ? formatTime(stats.connected_at) : "";
+        row.cells[7].innerText = stats ? formatTime(stats.last_activity) : "";
+        for (const i of [2, 3, 4, 5]) row.cells[i].classList.add('right-align');
+    });
+}
+function setSize(kind, id, size) {
+    previousSize[kind].set(id, state[kind].get(id) ?? size);
+    state[kind].set(id, size);
+}
+function setEnergy(id, value, loss) {
+    const now = performance.now();
+    const old = state.energy.get(id);
+    if (!old) return false;
+    // 1tick(50ms)あたりの増減
+    const ticks = (now - old.timestamp) / 50;
+    const rate = ticks > 0 ? Math.trunc((value - old.value) / ticks) : 0;
+    state.energy.set(id, {...old, value, loss, rate, timestamp: now});
+    return true;
+}
+async function fetchItem() {
+    const response = await fetch('/api/list/item_frequency.json');
+    const data = await response.json();
+    state.item = new Map();
+    data.forEach(item => setSize('item', item.id, item.size));
+    renderItem();
+}
+async function fetchFluid() {
+    const response = await fetch('/api/list/fluid_frequency.json');
+    const data = await response.json();
+    state.fluid = new Map();
+    data.forEach(item => setSize('fluid', item.id, item.size));
+    renderFluid();
 }
 async function fetchEnergy() {
     const response = await fetch('/api/list/energy_frequency.json');
     const data = await response.json();
-    const table = document.getElementById('energy-list').getElementsByTagName('tbody')[0];
-
-    // 行数を調整
-    while (table.rows.length < data.length) {
-        table.insertRow();
-    }
-    while (table.rows.length > data.length) {
-        table.deleteRow(-1);
-    }
-    // 行を上から書き換え
-    data.forEach((item, index) => {
-        const row = table.rows[index];
-        let cell1 = row.cells[0];
-        let cell2 = row.cells[1];
-        if (!cell1) cell1 = row.insertCell(0);
-        if (!cell2) cell2 = row.insertCell(1);
-
-        const ids = item.id.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
-        const localeText = item.id.split(',').map(id => localeColour[locale][id] || id).join(', ');
-        cell1.innerHTML = `<span>`+ids + '</span> ' + `<span class="txt freq-guide">${localeText}</span>`;
-
-        const previousItem = previousItemData.energy[index] || {};
-        const difference = Math.trunc((item.value - (previousItem.value || 0))*((performance.now()-previousEnergyTimestamp)/1000)/20);
-        const differenceText = difference > 0 ? `+${difference.toLocaleString()}` : difference;
-        cell2.innerHTML = `${item.value.toLocaleString()} <span style="width:100px" class="diff-value ${difference > 0 ? 'add' : difference < 0 ? 'sub' : 'zero'}">${difference==0?"±":""}${differenceText.toLocaleString()}RF/t</span>`;
-        cell2.classList.add('right-align');
+    const now = performance.now();
+    data.forEach(item => {
+        if (!setEnergy(item.id, item.value, item.loss)) {
+            state.energy.set(item.id, {...item, rate: 0, timestamp: now});
+        }
+        state.energy.get(item.id).capacity = item.capacity;
     });
-    previousEnergyTimestamp=performance.now();
-    // 現在のデータを保存
-    previousItemData.energy = data;
+    renderEnergy();
 }
 async function fetchClients() {
     const response = await fetch('/api/list/clients.json');
     const data = await response.json();
-    const list = document.getElementById('host-list');
-    list.innerHTML="";
-    data.forEach((item, index) => {
-        let li=element=document.createElement("li");
-        let span=element=document.createElement("span");
-        span.innerText=item.name;
-        li.appendChild(span);
-        span=element=document.createElement("span");
-        span.innerText=" "+item.sync+"ms";
-        li.appendChild(span);
-        list.appendChild(li);
+    state.client = new Map(data.map(item => [item.id, item]));
+    renderClients();
+}
+function renderTop(tableId, totals, unit) {
+    const table = document.getElementById(tableId).getElementsByTagName('tbody')[0];
+    const top = Object.entries(totals).sort((a, b) => b[1] - a[1]).slice(0, SUMMARY_TOP);
+    resizeTable(table, top.length);
+    top.forEach(([id, count], index) => {
+        const row = table.rows[index];
+        const cell1 = row.cells[0] || row.insertCell(0);
+        const cell2 = row.cells[1] || row.insertCell(1);
+        cell1.innerHTML = `<a href="/search.html?q=${encodeURIComponent(id)}&lang=${locale}"></a>`;
+        cell1.firstChild.textContent = id;
+        cell2.innerText = count.toLocaleString() + unit;
+        cell2.classList.add('right-align');
     });
 }
+async function fetchSummary() {
+    const response = await fetch('/api/summary.json');
+    const data = await response.json();
+    const fluid = Object.values(data.fluids).reduce((a, b) => a + b, 0);
+    document.getElementById('summary-totals').innerText = localeText[locale].summaryTotals({...data, fluid});
+    renderTop('summary-items', data.items, '');
+    renderTop('summary-fluids', data.fluids, 'mB');
+}
+// 変更通知が続いても一定間隔でしか取得しない
+function scheduleSummary() {
+    if (summaryTimer) return;
+    summaryTimer = setTimeout(() => {
+        summaryTimer = null;
+        fetchSummary();
+    }, SUMMARY_INTERVAL_MILLIS);
+}
 async function fetchData() {
-    await Promise.all([fetchItem(),fetchFluid(),fetchEnergy(),fetchClients()]);
+    await Promise.all([fetchItem(),fetchFluid(),fetchEnergy(),fetchClients(),fetchSummary()]);
+}
+// サーバーからの変更通知を反映する
+function applyEvents(events) {
+    const dirty = new Set();
+    let unknownEnergy = false;
+    events.forEach(event => {
+        switch (event.type) {
+            case 'item_frequency':
+                setSize('item', event.id, event.size);
+                dirty.add('item');
+                break;
+            case 'fluid_frequency':
+                setSize('fluid', event.id, event.size);
+                dirty.add('fluid');
+                break;
+            case 'energy':
+                // 容量は一覧から取得する
+                if (!setEnergy(event.id, event.value, event.loss)) unknownEnergy = true;
+                dirty.add('energy');
+                break;
+            case 'frequency_removed':
+                state[event.kind].delete(event.id);
+                dirty.add(event.kind);
+                break;
+            case 'client_connected':
+                state.client.set(event.id, {id: event.id, name: event.name, sync: 0});
+                dirty.add('client');
+                break;
+            case 'client_updated':
+                state.client.set(event.id, {id: event.id, name: event.name, sync: event.sync, stats: event.stats});
+                dirty.add('client');
+                break;
+            case 'client_disconnected':
+                state.client.delete(event.id);
+                dirty.add('client');
+                break;
+        }
+    });
+    if (dirty.has('item')) renderItem();
+    if (dirty.has('fluid')) renderFluid();
+    if (dirty.has('energy')) renderEnergy();
+    if (dirty.has('client')) renderClients();
+    if (unknownEnergy) fetchEnergy();
+    if (dirty.size > 0) scheduleSummary();
 }
 window.onload = async function () {
     await fetchData();
-    setInterval(fetchData, 1000); // 1秒毎にfetchDataを実行
+    const source = new EventSource('/api/events');
+    source.addEventListener('update', e => applyEvents(JSON.parse(e.data)));
+    // 取りこぼし・再接続時は一覧を取得し直す
+    source.addEventListener('resync', fetchData);
+    source.addEventListener('open', fetchData);
 };
 
 // Resizable table container
@@ -1,5 +1,7 @@
 let previousItemData = [];
 const freq = new URLSearchParams(window.location.search).get('freq');
+// 1ページの行数
+const PAGE_SIZE = 200;
 // ロケールによって表示するテキストを変更する
 window.addEventListener("load", function () {
     document.getElementById('item-info-title').innerText = localeText[locale].itemDetailInfoTitle;
@@ -10,24 +12,77 @@ window.addEventListener("load", function () {
     const f = freq.toLocaleLowerCase();
     const ids = f.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
     const text = f.split(',').map(id => localeColour[locale][id] || id).join(', ');
-    document.getElementById('channel-title').innerHTML = ids + `<p class="txt freq-guide">${text}</p>`;;
+    const history = `<a class="txt" href="/history.html?freq=${encodeURIComponent(freq)}&lang=${locale}">${localeText[locale].historyLink}</a>`;
+    document.getElementById('channel-title').innerHTML = ids + `<p class="txt freq-guide">${text}</p>` + history;
+
+    const parms = new URLSearchParams(window.location.search);
+    const searchBox = document.getElementById('item-search');
+    searchBox.placeholder = localeText[locale].searchPlaceholder;
+    searchBox.value = parms.get('q') || '';
+    document.getElementById('item-aggregate').checked = parms.get('aggregate') === 'true';
+    document.getElementById('aggregate-label').innerText = localeText[locale].aggregateLabel;
+    document.getElementById('prev-page').innerText = localeText[locale].prevPage;
+    document.getElementById('next-page').innerText = localeText[locale].nextPage;
 });
 
+function setParam(name, value) {
+    const parms = new URLSearchParams(window.location.search);
+    if (value) parms.set(name, value);
+    else parms.delete(name);
+    if (name !== 'page') parms.delete('page');
+    const url = new URL(window.location.pathname + "?" + parms, window.location.href);
+    window.location.href = url.toString();
+}
+function search(q) {
+    // "mekanism:"のようにコロンで終わる場合はMod IDで絞り込む
+    setParam('q', q);
+}
+function aggregate(checked) {
+    setParam('aggregate', checked ? 'true' : '');
+}
+function currentPage() {
+    return Number(new URLSearchParams(window.location.search).get('page')) || 0;
+}
+function movePage(delta) {
+    setParam('page', String(Math.max(0, currentPage() + delta)));
+}
+
 function sortBy(sort_by){
     const current_sort_by=new URLSearchParams(window.location.search).get('sort_by');
     const parms=new URLSearchParams(window.location.search);
     if(current_sort_by!=sort_by)parms.set("sort_by",sort_by);
     else parms.delete("sort_by");
+    parms.delete("page");
     const url = new URL(window.location.pathname+"?"+parms,window.location.href);
     window.location.href = url.toString();
 }
 
 async function fetchItem() {
+    const parms = new URLSearchParams(window.location.search);
     const url = new URL('/api/list/items.json', window.location.origin);
     url.searchParams.set('frequency', freq);
+    const q = parms.get('q') || '';
+    if (q.endsWith(':')) {
+        url.searchParams.set('modid', q.slice(0, -1));
+    } else if (q) {
+        url.searchParams.set('q', q);
+    }
+    // 並べ替え・絞り込み・ページ分けはサーバーで行う
+    const sort_by = parms.get('sort_by');
+    if (sort_by) url.searchParams.set('sort', sort_by);
+    const aggregated = parms.get('aggregate') === 'true';
+    if (aggregated) url.searchParams.set('aggregate', 'true');
+    const page = currentPage();
+    url.searchParams.set('offset', page * PAGE_SIZE);
+    url.searchParams.set('limit', PAGE_SIZE);
     const response = await fetch(url);
+    const total = Number(response.headers.get('x-total-count')) || 0;
     const data = await response.json();
     const table = document.getElementById('item-list').getElementsByTagName('tbody')[0];
+    const first = Math.min(total, page * PAGE_SIZE + 1);
+    document.getElementById('page-info').innerText = `${first}-${page * PAGE_SIZE + data.length} / ${total.toLocaleString()}`;
+    document.getElementById('prev-page').disabled = page === 0;
+    document.getElementById('next-page').disabled = (page + 1) * PAGE_SIZE >= total;
 
     // 行数を調整
     while (table.rows.length < data.length) {
@@ -36,20 +91,6 @@ async function fetchItem() {
     while (table.rows.length > data.length) {
         table.deleteRow(-1);
     }
-    const sort_by=new URLSearchParams(window.location.search).get('sort_by');
-    if(sort_by==="count"){
-        data.sort((a,b)=>b.count-a.count);
-    }else if(sort_by==="name"){
-        data.sort((a,b)=>{
-            const a_name=String(a.name).split(":");
-            const b_name=String(b.name).split(":");
-            return (b_name[1]+b_name[0])>(a_name[1]+a_name[0])?-1:1;
-        });
-    }if(sort_by==="modid"){
-        const a_name=String(a.name).split(":");
-        const b_name=String(b.name).split(":");
-        return b_name[0]>a_name[0]?-1:1;
-    }
     // 行を上から書き換え
     data.forEach((item, index) => {
         const row = table.rows[index];
@@ -62,7 +103,22 @@ async function fetchItem() {
         const split=String(item.name).split(":");
         cell1.innerHTML = split[0];
         cell1.classList.add('right-align');
-        cell2.innerHTML = split[1];
+        cell2.textContent = split[1];
+        if (item.display_name) {
+            const displayName = document.createElement('i');
+            displayName.textContent = ` "${item.display_name}"`;
+            cell2.appendChild(displayName);
+        }
+        if (aggregated) {
+            // まとめた行には個別のスタックが無い
+            cell2.title = "";
+            row.onclick = null;
+            cell3.title = `${item.stacks.toLocaleString()} ${localeText[locale].stacksTitle}`;
+        } else {
+            cell2.title = item.enchantments.map(e => `${e.id} ${e.level}`).join('\n');
+            row.onclick = () => showDetail(item.id);
+            cell3.title = "";
+        }
         cell3.innerHTML = item.count.toLocaleString();
         cell3.classList.add('right-align');
     });
@@ -71,13 +127,27 @@ async function fetchItem() {
     previousItemData = data;
 }
 
+function showDetail(id) {
+    const url = new URL('/api/item.json', window.location.origin);
+    url.searchParams.set('frequency', freq);
+    url.searchParams.set('id', id);
+    window.open(url.toString(), '_blank');
+}
+
 async function fetchData() {
     await fetchItem();
 }
 
 window.onload = function () {
     fetchData();
-    setInterval(fetchData, 1000); // 1秒毎にfetchDataを実行
+    // この周波数が変更された時だけ取得し直す
+    const source = new EventSource('/api/events');
+    source.addEventListener('update', e => {
+        const events = JSON.parse(e.data);
+        if (events.some(event => event.id === freq && (event.type === 'item_frequency' || event.type === 'frequency_removed'))) fetchData();
+    });
+    source.addEventListener('resync', fetchData);
+    source.addEventListener('open', fetchData);
 };
 
 // Resizable table container
@@ -59,6 +59,32 @@ const localeText = {
         fluidAmountHeader: "量",
         fluidTypeHeader: "種類",
         clientHostName: "サーバー名",
+        hostNameHeader: "ホスト名",
+        hostFrequencyHeader: "チャンネル",
+        hostSyncHeader: "同期時間",
+        hostTrafficHeader: "受信 / 送信",
+        hostInsertedHeader: "搬入量",
+        hostTakenHeader: "搬出量",
+        hostConnectedHeader: "接続時刻",
+        hostActivityHeader: "最終通信",
+        searchPlaceholder: "アイテムID・Mod IDで検索",
+        aggregateLabel: "同じアイテムをまとめる",
+        prevPage: "前へ",
+        nextPage: "次へ",
+        stacksTitle: "スタック",
+        searchTitle: "検索",
+        summaryTitle: "全体の在庫",
+        summaryTotals: (s) => `アイテム ${s.item_count.toLocaleString()}個 (${s.item_stacks.toLocaleString()}スタック) / 液体 ${s.fluid.toLocaleString()}mB / エネルギー ${s.energy.toLocaleString()}RF / チャンネル数 アイテム${s.frequencies.item} 液体${s.frequencies.fluid} エネルギー${s.frequencies.energy} / 接続中 ${s.clients}台`,
+        summaryItemHeader: "多いアイテム",
+        summaryFluidHeader: "多い液体",
+        searchIdHeader: "アイテムID・液体ID",
+        globalSearchPlaceholder: "全チャンネルから検索 (例: diamond, *:ingot_*)",
+        historyTitle: "履歴",
+        historyLink: "履歴を見る",
+        itemStacksChart: "アイテムスタック数",
+        itemCountChart: "アイテム数",
+        fluidAmountChart: "液体量",
+        energyChart: "エネルギー量",
     },
     en: {
         itemInfoTitle: "Item Information",
@@ -79,6 +105,32 @@ const localeText = {
         fluidAmountHeader: "Amount",
         fluidTypeHeader: "Types",
         clientHostName: "ServerName",
+        hostNameHeader: "Host",
+        hostFrequencyHeader: "Channel",
+        hostSyncHeader: "Sync",
+        hostTrafficHeader: "Received / Sent",
+        hostInsertedHeader: "Inserted",
+        hostTakenHeader: "Taken",
+        hostConnectedHeader: "Connected",
+        hostActivityHeader: "Last Activity",
+        searchPlaceholder: "Search by item ID or mod ID",
+        aggregateLabel: "Group same items",
+        prevPage: "Prev",
+        nextPage: "Next",
+        stacksTitle: "stacks",
+        searchTitle: "Search",
+        summaryTitle: "Global Inventory",
+        summaryTotals: (s) => `Items ${s.item_count.toLocaleString()} (${s.item_stacks.toLocaleString()} stacks) / Fluid ${s.fluid.toLocaleString()}mB / Energy ${s.energy.toLocaleString()}RF / Channels: item ${s.frequencies.item}, fluid ${s.frequencies.fluid}, energy ${s.frequencies.energy} / Clients ${s.clients}`,
+        summaryItemHeader: "Top Items",
+        summaryFluidHeader: "Top Fluids",
+        searchIdHeader: "Item / Fluid ID",
+        globalSearchPlaceholder: "Search all channels (e.g. diamond, *:ingot_*)",
+        historyTitle: "History",
+        historyLink: "Show history",
+        itemStacksChart: "Item stacks",
+        itemCountChart: "Item count",
+        fluidAmountChart: "Fluid amount",
+        energyChart: "Energy",
     }
 };
 
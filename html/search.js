@@ -0,0 +1,51 @@
+// 全チャンネルからアイテム・液体を探す
+const query = new URLSearchParams(window.location.search).get('q') || '';
+// ロケールによって表示するテキストを変更する
+window.addEventListener("load", function () {
+    document.getElementById('search-title').innerText = localeText[locale].searchTitle;
+    document.getElementById('search-id-header').innerText = localeText[locale].searchIdHeader;
+    document.getElementById('search-channel-header').innerText = localeText[locale].channelHeader;
+    document.getElementById('search-amount-header').innerText = localeText[locale].amountHeader;
+    const searchBox = document.getElementById('search-box');
+    searchBox.placeholder = localeText[locale].globalSearchPlaceholder;
+    searchBox.value = query;
+});
+
+function search(q) {
+    const parms = new URLSearchParams(window.location.search);
+    if (q) parms.set('q', q);
+    else parms.delete('q');
+    window.location.href = window.location.pathname + "?" + parms;
+}
+
+function freqCell(cell, id, page) {
+    const f = id.toLocaleLowerCase();
+    const ids = f.split(',').map(id => `<div class="freq ${id}"></div>`).join('');
+    const text = f.split(',').map(id => localeColour[locale][id] || id).join(', ');
+    const link = `<a href="/${page}?freq=${encodeURIComponent(id)}&lang=${locale}">` + ids + '</a>';
+    cell.innerHTML = link + ' ' + `<span class="txt freq-guide">${text}</span>`;
+}
+
+async function fetchResult() {
+    const table = document.getElementById('search-list').getElementsByTagName('tbody')[0];
+    if (!query) return;
+    const url = new URL('/api/search.json', window.location.origin);
+    url.searchParams.set('q', query);
+    const response = await fetch(url);
+    const data = await response.json();
+    table.innerHTML = "";
+    data.forEach(hit => {
+        const row = table.insertRow();
+        const cell1 = row.insertCell(0);
+        const cell2 = row.insertCell(1);
+        const cell3 = row.insertCell(2);
+        cell1.textContent = hit.id;
+        freqCell(cell2, hit.frequency, hit.kind === 'fluid' ? 'fluids.html' : 'items.html');
+        const unit = hit.kind === 'fluid' ? 'mB' : '';
+        cell3.textContent = hit.count.toLocaleString() + unit;
+        cell3.title = `${hit.stacks.toLocaleString()} ${localeText[locale].stacksTitle}`;
+        cell3.classList.add('right-align');
+    });
+}
+
+window.onload = fetchResult;
@@ -0,0 +1,380 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+	sync::{mpsc, Mutex},
+};
+
+use crate::{client::ClientSession, config::Config, Frequency, GlobalObject};
+
+const AUDIT_FILE: &str = "audit.log";
+const AUDIT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const AUDIT_MAX_FILES: usize = 5;
+/// 書き込みが追いつかない間に溜められる件数 超えた分は捨てて警告する
+const AUDIT_QUEUE: usize = 10000;
+/// 終了時に書き残しを待つ上限
+pub(crate) const AUDIT_FLUSH_SECS: u64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+	Item,
+	Fluid,
+	Energy,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+	Insert,
+	Take,
+}
+/// 搬入出1件 1行のJSONとして書き込む
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+	/// UNIXミリ秒
+	pub time: i64,
+	/// TCPクライアントのセッションID HTTPからの搬入出は"http"、管理APIとCLIの削除・移動は"admin"と"cli"
+	pub client: String,
+	pub hostname: String,
+	pub frequency: String,
+	pub kind: Kind,
+	pub direction: Direction,
+	/// アイテムIDか液体名 エネルギーは無し
+	pub id: Option<String>,
+	/// アイテム数・液体量・エネルギー量
+	pub count: i64,
+}
+/// 記録は書き込みタスクに渡すだけなので搬入出を待たせない
+pub struct AuditLog {
+	tx: mpsc::Sender<AuditEntry>,
+	rx: Mutex<Option<mpsc::Receiver<AuditEntry>>>,
+}
+impl Default for AuditLog {
+	fn default() -> Self {
+		let (tx, rx) = mpsc::channel(AUDIT_QUEUE);
+		Self {
+			tx,
+			rx: Mutex::new(Some(rx)),
+		}
+	}
+}
+impl AuditLog {
+	pub fn record(&self, entry: AuditEntry) {
+		if entry.count == 0 {
+			return;
+		}
+		if let Err(mpsc::error::TrySendError::Full(entry)) = self.tx.try_send(entry) {
+			tracing::warn!(
+				frequency = entry.frequency,
+				"audit queue full, entry dropped"
+			);
+		}
+	}
+	/// 書き込みタスクに渡す前の記録を取り出す
+	#[cfg(test)]
+	pub(crate) async fn drain(&self) -> Vec<AuditEntry> {
+		let mut rx = self.rx.lock().await;
+		let rx = rx.as_mut().expect("audit loop not started");
+		std::iter::from_fn(|| rx.try_recv().ok()).collect()
+	}
+}
+impl GlobalObject {
+	/// TCPクライアント以外からの操作を記録する ホスト名は`client`と同じ
+	pub(crate) fn record_audit(
+		&self,
+		client: &str,
+		freq: &Frequency,
+		kind: Kind,
+		direction: Direction,
+		id: Option<&str>,
+		count: i64,
+	) {
+		self.audit.record(AuditEntry {
+			time: chrono::Utc::now().timestamp_millis(),
+			client: client.into(),
+			hostname: client.into(),
+			frequency: freq.0.clone(),
+			kind,
+			direction,
+			id: id.map(|id| id.to_owned()),
+			count,
+		});
+	}
+}
+pub(crate) fn audit_file(config: &Config) -> String {
+	config
+		.audit_file
+		.clone()
+		.unwrap_or_else(|| AUDIT_FILE.into())
+}
+/// `audit.log.1`が一つ前 数字が大きいほど古い
+fn rotated(path: &str, n: usize) -> String {
+	format!("{}.{}", path, n)
+}
+/// 記録をファイルに追記し、大きくなったら古いファイルへ回す 終了時は溜まっている分を書き切ってから戻る
+pub(crate) async fn audit_loop(go: Arc<GlobalObject>) {
+	use futures::{future::FutureExt, pin_mut};
+	let Some(mut rx) = go.audit.rx.lock().await.take() else {
+		return;
+	};
+	let mut shutdown = go.shutdown.subscribe();
+	let mut writer = None;
+	loop {
+		let entry = {
+			let recv = rx.recv().fuse();
+			let stop = shutdown.wait_for(|stop| *stop).fuse();
+			pin_mut!(recv, stop);
+			futures::select! {
+				entry = recv => entry,
+				_ = stop => None,
+			}
+		};
+		let Some(entry) = entry else {
+			break;
+		};
+		let mut entries = vec![entry];
+		while let Ok(entry) = rx.try_recv() {
+			entries.push(entry);
+		}
+		flush(&go, &mut writer, &entries).await;
+	}
+	//以降の記録は受け付けず、溜まっている分だけ読み出す
+	rx.close();
+	let mut entries = Vec::new();
+	while let Some(entry) = rx.recv().await {
+		entries.push(entry);
+	}
+	if !entries.is_empty() {
+		flush(&go, &mut writer, &entries).await;
+	}
+}
+async fn flush(
+	go: &GlobalObject,
+	writer: &mut Option<BufWriter<tokio::fs::File>>,
+	entries: &[AuditEntry],
+) {
+	let config = go.config.read().await.clone();
+	let path = audit_file(&config);
+	if let Err(e) = write_entries(writer, &path, entries).await {
+		tracing::error!(path, error = %e, "audit log error");
+		*writer = None;
+		return;
+	}
+	let max_bytes = config.audit_max_bytes.unwrap_or(AUDIT_MAX_BYTES);
+	let max_files = config.audit_max_files.unwrap_or(AUDIT_MAX_FILES);
+	let size = tokio::fs::metadata(&path).await.map(|m| m.len());
+	if size.is_ok_and(|size| size >= max_bytes) {
+		*writer = None;
+		if let Err(e) = rotate(&path, max_files).await {
+			tracing::error!(path, error = %e, "audit log rotate error");
+		}
+	}
+}
+async fn write_entries(
+	writer: &mut Option<BufWriter<tokio::fs::File>>,
+	path: &str,
+	entries: &[AuditEntry],
+) -> Result<(), tokio::io::Error> {
+	if writer.is_none() {
+		let file = tokio::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.await?;
+		*writer = Some(BufWriter::new(file));
+	}
+	let w = writer.as_mut().unwrap();
+	for entry in entries {
+		let mut line = serde_json::to_vec(entry).map_err(tokio::io::Error::other)?;
+		line.push(b'\n');
+		w.write_all(&line).await?;
+	}
+	w.flush().await
+}
+async fn rotate(path: &str, max_files: usize) -> Result<(), tokio::io::Error> {
+	if max_files == 0 {
+		return tokio::fs::remove_file(path).await;
+	}
+	for n in (1..max_files).rev() {
+		match tokio::fs::rename(rotated(path, n), rotated(path, n + 1)).await {
+			Err(e) if e.kind() != tokio::io::ErrorKind::NotFound => return Err(e),
+			_ => {}
+		}
+	}
+	tokio::fs::rename(path, rotated(path, 1)).await
+}
+/// 検索条件 未指定の項目は絞り込まない
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+	pub frequency: Option<String>,
+	/// セッションIDかホスト名
+	pub client: Option<String>,
+	/// UNIXミリ秒
+	pub since: Option<i64>,
+	pub until: Option<i64>,
+	/// 新しいものから最大何件 未指定は1000
+	pub limit: Option<usize>,
+}
+impl AuditQuery {
+	fn matches(&self, entry: &AuditEntry) -> bool {
+		self.frequency
+			.as_ref()
+			.is_none_or(|f| *f == entry.frequency)
+			&& self
+				.client
+				.as_ref()
+				.is_none_or(|c| *c == entry.client || *c == entry.hostname)
+			&& self.since.is_none_or(|t| entry.time >= t)
+			&& self.until.is_none_or(|t| entry.time < t)
+	}
+}
+/// 古いファイルから順に読み、条件に合う新しい方から`limit`件を古い順で返す
+pub async fn query(path: &str, query: &AuditQuery) -> Result<Vec<AuditEntry>, tokio::io::Error> {
+	let limit = query.limit.unwrap_or(1000);
+	let mut files = Vec::new();
+	let mut n = 1;
+	while tokio::fs::try_exists(rotated(path, n)).await? {
+		files.push(rotated(path, n));
+		n += 1;
+	}
+	files.reverse();
+	files.push(path.to_owned());
+	let mut entries = std::collections::VecDeque::new();
+	for file in files {
+		let file = match tokio::fs::File::open(&file).await {
+			Ok(file) => file,
+			Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => continue,
+			Err(e) => return Err(e),
+		};
+		let mut lines = BufReader::new(file).lines();
+		while let Some(line) = lines.next_line().await? {
+			//書き込み途中の行は読み飛ばす
+			let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+				continue;
+			};
+			if query.matches(&entry) {
+				entries.push_back(entry);
+				if entries.len() > limit {
+					entries.pop_front();
+				}
+			}
+		}
+	}
+	Ok(entries.into())
+}
+impl ClientSession {
+	/// 監査ログとセッションの統計に記録する
+	pub(crate) async fn audit(
+		&self,
+		kind: Kind,
+		direction: Direction,
+		id: Option<&str>,
+		count: i64,
+	) {
+		let (client, hostname) = {
+			let mut meta = self.meta.lock().await;
+			meta.stats.moved(kind, direction, count);
+			(meta.id.to_string(), meta.hostname.clone())
+		};
+		self.go.audit.record(AuditEntry {
+			time: chrono::Utc::now().timestamp_millis(),
+			client,
+			hostname,
+			frequency: self.freq().0.clone(),
+			kind,
+			direction,
+			id: id.map(|id| id.to_owned()),
+			count,
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::{AuditEntry, AuditQuery, Direction, Kind};
+	use crate::{config::Config, GlobalObject};
+
+	fn entry(time: i64, frequency: &str, hostname: &str) -> AuditEntry {
+		AuditEntry {
+			time,
+			client: format!("id-{}", hostname),
+			hostname: hostname.into(),
+			frequency: frequency.into(),
+			kind: Kind::Item,
+			direction: Direction::Take,
+			id: Some("minecraft:stone".into()),
+			count: 64,
+		}
+	}
+	#[test]
+	fn write_rotate_query() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+				tokio::fs::create_dir_all(&dir).await.unwrap();
+				let path = dir.join("audit.log").to_string_lossy().into_owned();
+				let mut writer = None;
+				for i in 0..6 {
+					let host = if i % 2 == 0 { "a" } else { "b" };
+					let e = entry(i, "RED", host);
+					super::write_entries(&mut writer, &path, &[e])
+						.await
+						.unwrap();
+					if i % 2 == 1 {
+						writer = None;
+						super::rotate(&path, 2).await.unwrap();
+					}
+				}
+				//最も古い2件は回転で消える
+				let all = super::query(&path, &AuditQuery::default()).await.unwrap();
+				assert_eq!(all.iter().map(|e| e.time).collect::<Vec<_>>(), [2, 3, 4, 5]);
+				let query = AuditQuery {
+					client: Some("a".into()),
+					since: Some(3),
+					..Default::default()
+				};
+				let a = super::query(&path, &query).await.unwrap();
+				assert_eq!(a, [entry(4, "RED", "a")]);
+				let query = AuditQuery {
+					limit: Some(1),
+					..Default::default()
+				};
+				let last = super::query(&path, &query).await.unwrap();
+				assert_eq!(last, [entry(5, "RED", "b")]);
+				tokio::fs::remove_dir_all(&dir).await.unwrap();
+			});
+	}
+	#[test]
+	fn flush_on_shutdown() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+				tokio::fs::create_dir_all(&dir).await.unwrap();
+				let path = dir.join("audit.log").to_string_lossy().into_owned();
+				let config = Config {
+					audit_file: Some(path.clone()),
+					..Default::default()
+				};
+				let go = Arc::new(GlobalObject::new(config));
+				let audit = tokio::spawn(super::audit_loop(go.clone()));
+				//書き込みタスクが動く前に終了しても記録は残る
+				for i in 0..3 {
+					go.audit.record(entry(i, "RED", "a"));
+				}
+				go.shutdown.send_replace(true);
+				audit.await.unwrap();
+				let all = super::query(&path, &AuditQuery::default()).await.unwrap();
+				assert_eq!(all.len(), 3);
+				tokio::fs::remove_dir_all(&dir).await.unwrap();
+			});
+	}
+}
@@ -1,36 +1,250 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use tokio::io::{
 	AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
 };
 
+use serde::Serialize;
+
 use crate::{
-	fluid::{self, Fluids},
-	item::{self, Items},
+	audit::{Direction, Kind},
+	config::{self, Config},
+	energy::energy_capacity,
+	fluid,
+	health::SaveLoad,
+	item::{self, ItemOrder},
+	logging,
+	meta::StackMeta,
+	nbt_store::NbtHash,
 	read_string, write_string, Frequency, GlobalObject,
 };
 
-const SAVE_DATA_FORMAT: i64 = 3;
+const SAVE_DATA_FORMAT: i64 = 6;
+pub const SAVE_FILE: &str = "save.dat.gz";
+/// 自動保存が無効の間に設定の再読み込みを確認する間隔
+const AUTOSAVE_POLL_SECS: u64 = 60;
 
 pub(crate) async fn cli(go: Arc<GlobalObject>) {
 	let mut stdout = BufReader::new(tokio::io::stdin()).lines();
 	while let Ok(Some(text)) = stdout.next_line().await {
-		match text.as_str() {
-			"load" => println!("{:?}", load_file("save.dat.gz", &go).await),
-			"save" => println!("{:?}", save_file("save.dat.gz", &go).await),
-			"stop" => {
-				println!("{:?}", save_file("save.dat.gz", &go).await);
-				std::process::exit(0);
+		let (command, args) = text.split_once(' ').unwrap_or((&text, ""));
+		match command {
+			"load" => println!("{:?}", load_file(SAVE_FILE, &go).await),
+			//save force で読み込みに失敗していても保存する
+			"save" => println!("{:?}", save_file(SAVE_FILE, &go, args == "force").await),
+			"reload" => println!("{:?}", reload_config(config::CONFIG_FILE, &go).await),
+			"stop" => println!("{:?}", stop(&go, args == "force").await),
+			//log warn,FedStorageServer_rs=debug
+			"log" => println!("{:?}", logging::set_level(args)),
+			//clear RED, RED, RED
+			"clear" => {
+				let cleared = clear_frequency(&go, &Frequency(args.into()), "cli").await;
+				println!("{:?}", cleared);
 			}
+			//move RED, RED, RED -> BLUE, BLUE, BLUE
+			"move" => match args.split_once(" -> ") {
+				Some((from, to)) => {
+					let (from, to) = (Frequency(from.into()), Frequency(to.into()));
+					println!("{:?}", move_frequency(&go, &from, &to, "cli").await);
+				}
+				None => println!("Usage: move <from> -> <to>"),
+			},
 			_ => {
 				println!("Command Not Found");
 			}
 		}
 	}
 }
-pub async fn save_file(path: &str, go: &GlobalObject) -> Result<(), tokio::io::Error> {
-	let mut w = tokio::fs::File::create(path).await?;
-	save(&mut w, go).await
+pub async fn reload_config(path: &str, go: &GlobalObject) -> Result<(), tokio::io::Error> {
+	let config = Config::load_file(path).await?;
+	logging::apply(&config)?;
+	*go.config.write().await = config;
+	Ok(())
+}
+/// 保存してからHTTPサーバーを止める 保存に失敗した場合は止めない
+pub async fn stop(go: &GlobalObject, force: bool) -> Result<(), tokio::io::Error> {
+	save_file(SAVE_FILE, go, force).await?;
+	go.shutdown.send_replace(true);
+	Ok(())
+}
+/// 削除・移動した量
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Transferred {
+	pub items: usize,
+	pub fluids: usize,
+	pub energy: i64,
+}
+/// 周波数の中身を全て削除する 監査ログには`client`からの搬出として記録する
+pub async fn clear_frequency(go: &GlobalObject, freq: &Frequency, client: &str) -> Transferred {
+	let mut cleared = Transferred::default();
+	if let Some(items) = go.item_buffers.get(freq) {
+		let stacks = std::mem::take(&mut *items.data.write().await);
+		items.changed(0);
+		cleared.items = stacks.len();
+		for is in &stacks {
+			let count = is.count as i64;
+			go.record_audit(
+				client,
+				freq,
+				Kind::Item,
+				Direction::Take,
+				Some(&is.id),
+				count,
+			);
+		}
+	}
+	if let Some(fluids) = go.fluid_buffers.get(freq) {
+		let stacks = std::mem::take(&mut *fluids.data.write().await);
+		fluids.changed(0);
+		cleared.fluids = stacks.len();
+		for fs in stacks.values() {
+			let name = Some(fs.name.as_str());
+			go.record_audit(client, freq, Kind::Fluid, Direction::Take, name, fs.count);
+		}
+	}
+	if let Some(energy) = go.energy_buffers.get(freq) {
+		cleared.energy = energy.sub(i64::MAX);
+		let count = cleared.energy;
+		go.record_audit(client, freq, Kind::Energy, Direction::Take, None, count);
+	}
+	cleared
+}
+/// 周波数の中身を別の周波数へ移す 入りきらないものは元に残す
+pub async fn move_frequency(
+	go: &GlobalObject,
+	from: &Frequency,
+	to: &Frequency,
+	client: &str,
+) -> Result<Transferred, tokio::io::Error> {
+	if from == to {
+		return Err(tokio::io::Error::other("Same Frequency"));
+	}
+	let mut moved = Transferred::default();
+	if let Some(src) = go.item_buffers.get(from) {
+		let dst = go.items(to);
+		//移動先の空きの分だけ先頭から取り出す 搬入時刻は変えないので期限もそのまま
+		let mut stacks = src
+			.take_items(dst.free().await as i32, &ItemOrder::Fifo)
+			.await;
+		let offered = stacks
+			.iter()
+			.map(|is| (is.id.clone(), is.count as i64))
+			.collect::<Vec<_>>();
+		let total = stacks.len();
+		dst.move_items(&mut stacks).await;
+		moved.items = total - stacks.len();
+		for (id, count) in &offered[..moved.items] {
+			let id = Some(id.as_str());
+			go.record_audit(client, from, Kind::Item, Direction::Take, id, *count);
+			go.record_audit(client, to, Kind::Item, Direction::Insert, id, *count);
+		}
+		//同時に搬入されて入りきらなかった分は元に戻す
+		src.restore_items(&mut stacks).await;
+		if !stacks.is_empty() {
+			let count = stacks.len();
+			tracing::warn!(
+				count,
+				frequency = from.0,
+				"drop stacks that could not be moved back"
+			);
+			for is in &stacks {
+				let count = is.count as i64;
+				go.record_audit(
+					client,
+					from,
+					Kind::Item,
+					Direction::Take,
+					Some(&is.id),
+					count,
+				);
+			}
+		}
+	}
+	if let Some(src) = go.fluid_buffers.get(from) {
+		let fluids = std::mem::take(&mut *src.data.write().await);
+		moved.fluids = fluids.len();
+		src.changed(0);
+		let dst = go.fluids(to);
+		for fs in fluids.into_values() {
+			let name = Some(fs.name.as_str());
+			go.record_audit(client, from, Kind::Fluid, Direction::Take, name, fs.count);
+			go.record_audit(client, to, Kind::Fluid, Direction::Insert, name, fs.count);
+			dst.merge_fluid(fs).await;
+		}
+	}
+	if let Some(src) = go.energy_buffers.get(from) {
+		let value = src.sub(i64::MAX);
+		let capacity = energy_capacity(&*go.config.read().await, to);
+		let reject = go.energy(to).add(value, capacity);
+		src.merge(reject);
+		moved.energy = value - reject;
+		let count = moved.energy;
+		go.record_audit(client, from, Kind::Energy, Direction::Take, None, count);
+		go.record_audit(client, to, Kind::Energy, Direction::Insert, None, count);
+	}
+	Ok(moved)
+}
+/// 一時ファイルに書いてから置き換える 保存は同時に1つだけ行う 結果は`/readyz`に反映する
+///
+/// 起動時の読み込みが終わっていない・失敗した場合は、読めなかった保存ファイルを上書きしないよう`force`が無ければ保存しない
+pub async fn save_file(path: &str, go: &GlobalObject, force: bool) -> Result<(), tokio::io::Error> {
+	if !force && !go.readiness().save_loaded {
+		return Err(tokio::io::Error::other("Save File Not Loaded"));
+	}
+	let _guard = go.save_lock.lock().await;
+	let result = async {
+		let tmp = format!("{}.tmp", path);
+		let mut w = tokio::fs::File::create(&tmp).await?;
+		save(&mut w, go).await?;
+		w.sync_all().await?;
+		drop(w);
+		tokio::fs::rename(tmp, path).await
+	}
+	.await;
+	go.health.saved(&result);
+	result
+}
+/// 起動時の読み込み 保存ファイルが無ければ空のまま始める
+pub(crate) async fn autoload(go: &GlobalObject) {
+	if !go.config.read().await.autoload {
+		go.health.save_loaded(SaveLoad::NotConfigured);
+		return;
+	}
+	match load_file(SAVE_FILE, go).await {
+		Ok(()) => tracing::info!(path = SAVE_FILE, "save file loaded"),
+		Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => {
+			go.health.save_loaded(SaveLoad::NotFound);
+		}
+		Err(e) => {
+			//読み込めていない状態で保存すると上書きしてしまうので、強制しない限り保存しない
+			tracing::error!(path = SAVE_FILE, error = %e, "save file load error");
+			go.health.save_loaded(SaveLoad::Failed);
+		}
+	}
+}
+/// 設定された間隔で保存する 起動時の読み込みが終わるまでは保存せず、終了が決まったら止める
+pub(crate) async fn autosave_loop(go: Arc<GlobalObject>) {
+	let mut shutdown = go.shutdown.subscribe();
+	loop {
+		let interval = go.config.read().await.autosave_interval_secs;
+		let secs = interval.unwrap_or(AUTOSAVE_POLL_SECS).max(1);
+		let stop = shutdown.wait_for(|stop| *stop);
+		if tokio::time::timeout(Duration::from_secs(secs), stop)
+			.await
+			.is_ok()
+		{
+			return;
+		}
+		if interval.is_none() || !go.readiness().save_loaded {
+			continue;
+		}
+		if *go.shutdown.borrow() {
+			return;
+		}
+		if let Err(e) = save_file(SAVE_FILE, &go, false).await {
+			tracing::error!(path = SAVE_FILE, error = %e, "autosave error");
+		}
+	}
 }
 pub async fn save<W: AsyncWrite + std::marker::Unpin>(
 	w: &mut W,
@@ -40,7 +254,12 @@ pub async fn save<W: AsyncWrite + std::marker::Unpin>(
 	let mut w = GzipEncoder::new(BufWriter::new(w));
 	w.write_i64(SAVE_DATA_FORMAT).await?;
 	{
-		let fluid_buffers = go.fluid_buffers.read().await;
+		let mut fluid_buffers = Vec::new();
+		for (freq, fluids) in go.fluid_buffers.snapshot() {
+			if fluids.len().await > 0 {
+				fluid_buffers.push((freq, fluids));
+			}
+		}
 		w.write_i32(fluid_buffers.len() as i32).await?;
 		for (freq, fluids) in fluid_buffers.iter() {
 			write_string(&mut w, &freq.0).await?;
@@ -48,30 +267,61 @@ pub async fn save<W: AsyncWrite + std::marker::Unpin>(
 			w.write_i32(fluid_data.len() as i32).await?;
 			for fs in fluid_data.values() {
 				fs.write(&mut w).await?;
+				w.write_i64(fs.meta.inserted_at).await?;
 			}
 		}
 	}
 	{
-		let item_buffers = go.item_buffers.read().await;
+		let mut item_buffers = Vec::new();
+		for (freq, items) in go.item_buffers.snapshot() {
+			//NBTは共有されているので複製は軽い
+			let items = items.data.read().await.clone();
+			if !items.is_empty() {
+				item_buffers.push((freq, items));
+			}
+		}
+		//同じNBTは1度だけ書き込む
+		let mut nbt_table = HashMap::new();
+		for is in item_buffers.iter().flat_map(|(_, items)| items.iter()) {
+			if let Some(item::NBT::Extra(Some(gz))) = &is.nbt {
+				nbt_table.entry(*gz.hash()).or_insert_with(|| gz.clone());
+			}
+		}
+		w.write_i32(nbt_table.len() as i32).await?;
+		for (hash, gz) in nbt_table.iter() {
+			w.write_all(hash).await?;
+			w.write_i32(gz.as_gzip().len() as i32).await?;
+			w.write_all(gz.as_gzip()).await?;
+		}
 		w.write_i32(item_buffers.len() as i32).await?;
 		for (freq, items) in item_buffers.iter() {
 			write_string(&mut w, &freq.0).await?;
-			let items = items.data.read().await;
 			w.write_i32(items.len() as i32).await?;
 			for is in items.iter() {
 				is.write(&mut w).await?;
 			}
 			for is in items.iter() {
-				is.write_extra(&mut w).await?;
+				is.write_extra_hash(&mut w).await?;
+			}
+			for is in items.iter() {
+				w.write_i64(is.meta.inserted_at).await?;
+			}
+			for is in items.iter() {
+				w.write_u64(is.meta.id).await?;
 			}
 		}
 	}
 	{
-		let energy_buffers = go.energy_buffers.read().await;
+		let energy_buffers = go.energy_buffers.snapshot();
+		let energy_buffers = energy_buffers
+			.iter()
+			.map(|(freq, energy)| (freq, energy.value()))
+			.filter(|(_, value)| *value != 0)
+			.collect::<Vec<_>>();
 		w.write_i32(energy_buffers.len() as i32).await?;
-		for (freq, value) in energy_buffers.iter() {
+		for (freq, value) in energy_buffers {
 			write_string(&mut w, &freq.0).await?;
-			w.write_i64(*value).await?;
+			w.write_i64(value).await?;
 		}
 	}
 	w.shutdown().await?;
@@ -80,7 +330,9 @@ pub async fn save<W: AsyncWrite + std::marker::Unpin>(
 }
 pub async fn load_file(path: &str, go: &GlobalObject) -> Result<(), tokio::io::Error> {
 	let mut r = tokio::fs::File::open(path).await?;
-	load(&mut r, go).await
+	load(&mut r, go).await?;
+	go.health.save_loaded(SaveLoad::Loaded);
+	Ok(())
 }
 pub async fn load<R: AsyncRead + std::marker::Unpin>(
 	r: &mut R,
@@ -89,74 +341,99 @@ pub async fn load<R: AsyncRead + std::marker::Unpin>(
 	use async_compression::tokio::bufread::GzipDecoder;
 	let mut r = GzipDecoder::new(BufReader::new(r));
 	let version = r.read_i64().await?;
-	if version == 2 {
-		//V2はそのままV3デコーダで読み込める
+	if (2..=5).contains(&version) {
+		//V2はそのままV3デコーダで読み込める V3は搬入時刻が無い V4はNBTが共有されていない V5は番号が無い
 	} else if version != SAVE_DATA_FORMAT {
 		return Err(tokio::io::Error::other("Bad Data Format Version"));
 	}
+	let has_meta = version >= 4;
+	let has_nbt_table = version >= 5;
+	let has_id = version >= 6;
+	//搬入時刻が無いデータは読み込んだ時刻に搬入されたものとする
+	let loaded_at = StackMeta::now();
 	{
-		let mut fluid_buffers = go.fluid_buffers.write().await;
 		let fluid_freq_count = r.read_i32().await?;
 		for _ in 0..fluid_freq_count {
 			let freq = read_string(&mut r).await?;
-			let fluids = Fluids::new();
+			let fluids = go.fluids(&Frequency(freq));
 			let stack_count = r.read_i32().await?;
 			for _ in 0..stack_count {
-				let fs = fluid::FluidStack::read(&mut r).await?;
-				fluids.insert_fluid(fs).await;
-			}
-			let freq = Frequency(freq);
-			if let Some(old) = fluid_buffers.remove(&freq) {
-				for fs in old.data.read().await.values() {
-					fluids.insert_fluid(fs.clone()).await;
+				let mut fs = fluid::FluidStack::read(&mut r).await?;
+				fs.meta = loaded_at.renew();
+				if has_meta {
+					fs.meta.inserted_at = r.read_i64().await?;
 				}
+				fluids.merge_fluid(fs).await;
 			}
-			fluid_buffers.insert(freq, Arc::new(fluids));
 		}
 	}
 	{
-		let mut item_buffers = go.item_buffers.write().await;
+		//読み込み中に共有ストアから消えないように保持する
+		let mut nbt_table = Vec::new();
+		if has_nbt_table {
+			let nbt_count = r.read_i32().await?;
+			for _ in 0..nbt_count {
+				let mut hash = NbtHash::default();
+				r.read_exact(&mut hash).await?;
+				let len = r.read_i32().await?;
+				let len = len.try_into().map_err(tokio::io::Error::other)?;
+				let mut data = vec![0u8; len];
+				r.read_exact(&mut data).await?;
+				let gz = item::GzipNBT::from_gzip(data);
+				if *gz.hash() != hash {
+					return Err(tokio::io::Error::other("NBT Hash Mismatch"));
+				}
+				nbt_table.push(go.nbt_store.intern(gz)?);
+			}
+		}
 		let item_freq_count = r.read_i32().await?;
 		for _ in 0..item_freq_count {
 			let freq = read_string(&mut r).await?;
 			let freq = Frequency(freq);
-			let items = Items::new();
-			{
-				let mut item_data = items.data.write().await;
-				let stack_count = r.read_i32().await?;
-				let mut read_buffer = Vec::new();
-				for _ in 0..stack_count {
-					let is = item::ItemStack::read(&mut r).await?;
-					read_buffer.push(is);
+			let stack_count = r.read_i32().await?;
+			let mut read_buffer = Vec::new();
+			for _ in 0..stack_count {
+				let is = item::ItemStack::read(&mut r).await?;
+				read_buffer.push(is);
+			}
+			for is in read_buffer.iter_mut() {
+				if has_nbt_table {
+					is.read_extra_hash(&mut r, &go.nbt_store).await?;
+				} else {
+					is.read_extra(&mut r, &go.nbt_store).await?;
 				}
-				for is in read_buffer.iter_mut() {
-					is.read_extra(&mut r).await?;
+			}
+			for is in read_buffer.iter_mut() {
+				is.meta = loaded_at.renew();
+				if has_meta {
+					is.meta.inserted_at = r.read_i64().await?;
 				}
-				item_data.reserve(read_buffer.len());
-				for is in read_buffer {
-					item_data.push(is);
+			}
+			if has_id {
+				for is in read_buffer.iter_mut() {
+					is.meta = StackMeta::restore(is.meta.inserted_at, r.read_u64().await?);
 				}
-				if let Some(old) = item_buffers.remove(&freq) {
-					let old_items = old.data.read().await;
-					for is in old_items.iter() {
-						item_data.push(is.clone());
-					}
+			}
+			//読み込んだものを既存のものより先に
+			let items = go.items(&freq);
+			let mut item_data = items.data.write().await;
+			//実行中に読み込んだ場合は既存のスタックと番号が重ならないようにする
+			for is in read_buffer.iter_mut() {
+				if item_data.iter().any(|e| e.meta.id == is.meta.id) {
+					is.meta = is.meta.renew();
 				}
 			}
-			item_buffers.insert(freq, Arc::new(items));
+			item_data.splice(0..0, read_buffer);
+			items.changed(item_data.len());
 		}
 	}
 	{
-		let mut energy_buffers = go.energy_buffers.write().await;
 		let energy_freq_count = r.read_i32().await?;
 		for _ in 0..energy_freq_count {
 			let freq = read_string(&mut r).await?;
 			let freq = Frequency(freq);
-			let mut value = r.read_i64().await?;
-			if let Some(old) = energy_buffers.get(&freq) {
-				value = value.saturating_add(*old);
-			}
-			energy_buffers.insert(freq, value);
+			let value = r.read_i64().await?;
+			go.energy(&freq).merge(value);
 		}
 	}
 	Ok(())
@@ -165,10 +442,54 @@ pub async fn load<R: AsyncRead + std::marker::Unpin>(
 #[cfg(test)]
 mod tests {
 
-	use crate::{cli::load, GlobalObject};
+	use crate::{
+		audit::{Direction, Kind},
+		cli::load,
+		config::Config,
+		health::SaveLoad,
+		Frequency, GlobalObject,
+	};
 
 	use super::save;
 
+	#[test]
+	fn save_file() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let dir = std::env::temp_dir().join(format!("save-{}", uuid::Uuid::new_v4()));
+				tokio::fs::create_dir_all(&dir).await.unwrap();
+				let path = dir.join("save.dat.gz").to_string_lossy().into_owned();
+				let go = std::sync::Arc::new(GlobalObject::dummy().await);
+				//読み込みに失敗した保存ファイルは強制しない限り上書きしない
+				go.health.save_loaded(SaveLoad::Failed);
+				assert!(super::save_file(&path, &go, false).await.is_err());
+				assert!(!std::path::Path::new(&path).exists());
+				super::save_file(&path, &go, true).await.unwrap();
+				go.health.save_loaded(SaveLoad::Loaded);
+				//同時に保存しても壊れない
+				let (a, b) = futures::future::join(
+					super::save_file(&path, &go, false),
+					super::save_file(&path, &go, false),
+				)
+				.await;
+				assert!(a.is_ok() && b.is_ok());
+				assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+				let dst = GlobalObject::new(Config::default());
+				super::load_file(&path, &dst).await.unwrap();
+				assert_eq!(dst.item_buffers.snapshot().len(), 2);
+				//終了が決まったら自動保存を止める
+				let autosave = tokio::spawn(super::autosave_loop(go.clone()));
+				go.shutdown.send_replace(true);
+				tokio::time::timeout(std::time::Duration::from_secs(1), autosave)
+					.await
+					.unwrap()
+					.unwrap();
+				tokio::fs::remove_dir_all(&dir).await.unwrap();
+			});
+	}
 	#[test]
 	fn save_load() {
 		tokio::runtime::Builder::new_current_thread()
@@ -179,14 +500,21 @@ mod tests {
 				let src = GlobalObject::dummy().await;
 				let mut v = Vec::new();
 				save(&mut v, &src).await.unwrap();
-				let mut dst = GlobalObject::new();
-				load(&mut std::io::Cursor::new(&v), &mut dst).await.unwrap();
-				assert_eq!(
-					src.energy_buffers.read().await.iter().collect::<Vec<_>>(),
-					dst.energy_buffers.read().await.iter().collect::<Vec<_>>()
-				);
+				let dst = GlobalObject::new(Config::default());
+				load(&mut std::io::Cursor::new(&v), &dst).await.unwrap();
+				let energy_values = |go: &GlobalObject| {
+					let mut values = go
+						.energy_buffers
+						.snapshot()
+						.into_iter()
+						.map(|(f, energy)| (f, energy.value()))
+						.collect::<Vec<_>>();
+					values.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+					values
+				};
+				assert_eq!(energy_values(&src), energy_values(&dst));
 
-				let r = src.item_buffers.read().await;
+				let r = src.item_buffers.snapshot();
 				let mut src_items = futures::future::join_all({
 					r.iter()
 						.map(|(f, items)| async { (f.clone(), items.to_vec().await) })
@@ -195,7 +523,7 @@ mod tests {
 				.into_iter()
 				.collect::<Vec<_>>();
 				src_items.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
-				let r = dst.item_buffers.read().await;
+				let r = dst.item_buffers.snapshot();
 				let mut dst_items = futures::future::join_all({
 					r.iter()
 						.map(|(f, items)| async { (f.clone(), items.to_vec().await) })
@@ -205,8 +533,19 @@ mod tests {
 				.collect::<Vec<_>>();
 				dst_items.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
 				assert_eq!(src_items, dst_items);
+				let inserted_at = |items: &Vec<(_, Vec<crate::item::ItemStack>)>| {
+					let stacks = items.iter().flat_map(|(_, stacks)| stacks.iter());
+					stacks.map(|is| is.meta.inserted_at).collect::<Vec<_>>()
+				};
+				assert_eq!(inserted_at(&src_items), inserted_at(&dst_items));
+				//番号も保存される
+				let ids = |items: &Vec<(_, Vec<crate::item::ItemStack>)>| {
+					let stacks = items.iter().flat_map(|(_, stacks)| stacks.iter());
+					stacks.map(|is| is.meta.id).collect::<Vec<_>>()
+				};
+				assert_eq!(ids(&src_items), ids(&dst_items));
 
-				let r = src.fluid_buffers.read().await;
+				let r = src.fluid_buffers.snapshot();
 				let mut src_fluids = futures::future::join_all({
 					r.iter()
 						.map(|(f, fluids)| async { (f.clone(), fluids.to_vec().await) })
@@ -215,7 +554,7 @@ mod tests {
 				.into_iter()
 				.collect::<Vec<_>>();
 				src_fluids.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
-				let r = dst.fluid_buffers.read().await;
+				let r = dst.fluid_buffers.snapshot();
 				let mut dst_fluids = futures::future::join_all({
 					r.iter()
 						.map(|(f, fluids)| async { (f.clone(), fluids.to_vec().await) })
@@ -225,6 +564,120 @@ mod tests {
 				.collect::<Vec<_>>();
 				dst_fluids.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
 				assert_eq!(src_fluids, dst_fluids);
+				let inserted_at = |fluids: &Vec<(_, Vec<(_, crate::fluid::FluidStack)>)>| {
+					let stacks = fluids.iter().flat_map(|(_, stacks)| stacks.iter());
+					stacks
+						.map(|(_, fs)| fs.meta.inserted_at)
+						.collect::<Vec<_>>()
+				};
+				assert_eq!(inserted_at(&src_fluids), inserted_at(&dst_fluids));
+				//実行中に再度読み込んでも番号は重ならない
+				load(&mut std::io::Cursor::new(&v), &dst).await.unwrap();
+				for (_, items) in dst.item_buffers.snapshot() {
+					let data = items.data.read().await;
+					let mut ids = data.iter().map(|is| is.meta.id).collect::<Vec<_>>();
+					ids.sort();
+					ids.dedup();
+					assert_eq!(ids.len(), data.len());
+				}
+			});
+	}
+	#[test]
+	fn clear_and_move() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let red = Frequency("RED, RED, RED".into());
+				let white = Frequency("WHITE, WHITE, WHITE".into());
+				let moved = super::move_frequency(&go, &red, &white, "cli")
+					.await
+					.unwrap();
+				assert_eq!((moved.items, moved.fluids, moved.energy), (1, 1, 0));
+				assert_eq!(go.items(&red).len().await, 0);
+				assert_eq!(go.items(&white).len().await, 1);
+				assert_eq!(go.fluids(&white).len().await, 1);
+				//移動元からの搬出と移動先への搬入を記録する
+				let entries = go.audit.drain().await;
+				let found = entries
+					.iter()
+					.map(|e| (e.frequency.as_str(), e.kind, e.direction, e.count))
+					.collect::<Vec<_>>();
+				assert_eq!(
+					found,
+					[
+						(red.0.as_str(), Kind::Item, Direction::Take, 64),
+						(white.0.as_str(), Kind::Item, Direction::Insert, 64),
+						(
+							red.0.as_str(),
+							Kind::Fluid,
+							Direction::Take,
+							i32::MAX as i64 + 100
+						),
+						(
+							white.0.as_str(),
+							Kind::Fluid,
+							Direction::Insert,
+							i32::MAX as i64 + 100
+						),
+					]
+				);
+				assert!(entries.iter().all(|e| e.client == "cli"));
+				//容量を超えた分は元に残る
+				let moved = super::move_frequency(&go, &white, &red, "cli")
+					.await
+					.unwrap();
+				assert_eq!(moved.energy, u32::MAX as i64);
+				assert_eq!(go.energy_value(&white), 500);
+				assert!(super::move_frequency(&go, &red, &red, "cli").await.is_err());
+				let cleared = super::clear_frequency(&go, &red, "cli").await;
+				assert_eq!((cleared.items, cleared.fluids), (1, 1));
+				assert_eq!(cleared.energy, u32::MAX as i64);
+				assert_eq!(go.items(&red).len().await, 0);
+				assert_eq!(go.energy_value(&red), 0);
+				let entries = go.audit.drain().await;
+				let cleared = entries
+					.iter()
+					.filter(|e| e.frequency == red.0 && e.direction == Direction::Take)
+					.map(|e| e.kind)
+					.collect::<Vec<_>>();
+				assert_eq!(cleared, [Kind::Item, Kind::Fluid, Kind::Energy]);
+			});
+	}
+	#[test]
+	fn move_to_full() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let from = Frequency("FROM".into());
+				let to = Frequency("TO".into());
+				let stack = crate::item::ItemStack::dummy();
+				go.items(&from)
+					.insert_items(&mut vec![stack.clone(); 100])
+					.await;
+				go.items(&to).insert_items(&mut vec![stack; 98]).await;
+				let metas = |freq| {
+					let items = go.items(freq);
+					async move {
+						let data = items.data.read().await;
+						data.iter()
+							.map(|is| (is.meta.id, is.meta.inserted_at))
+							.collect::<Vec<_>>()
+					}
+				};
+				let before = metas(&from).await;
+				let moved = super::move_frequency(&go, &from, &to, "cli").await.unwrap();
+				assert_eq!(moved.items, 2);
+				assert_eq!(go.items(&from).len().await, 98);
+				assert_eq!(go.items(&to).len().await, 100);
+				//搬入時刻と番号は移動しても変わらない
+				assert_eq!(metas(&to).await[98..], before[..2]);
+				assert_eq!(metas(&from).await, before[2..]);
 			});
 	}
 }
@@ -1,30 +1,165 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+	collections::{BTreeMap, VecDeque},
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
 
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::FromPrimitive;
+use serde::Serialize;
 use tokio::{
 	io::{AsyncReadExt, AsyncWriteExt},
 	net::TcpStream,
 	sync::Mutex,
 };
 
-use crate::{read_string, Frequency, GlobalObject};
+use crate::{
+	audit::{Direction, Kind},
+	events::Event,
+	metrics::Counted,
+	read_string, Frequency, GlobalObject,
+};
 
 const CLIENT_VERSION: i64 = 7;
+/// 同期時間の統計に使う直近の回数
+const SYNC_WINDOW: usize = 100;
 
 pub(crate) struct ClientSession {
-	pub(crate) reader: tokio::net::tcp::OwnedReadHalf,
-	pub(crate) writer: tokio::net::tcp::OwnedWriteHalf,
+	pub(crate) reader: Counted<tokio::net::tcp::OwnedReadHalf>,
+	pub(crate) writer: Counted<tokio::net::tcp::OwnedWriteHalf>,
 	pack_start: chrono::DateTime<chrono::Utc>,
 	freq: Option<Frequency>,
 	pub(crate) meta: Arc<Mutex<ClientMeta>>,
 	pub(crate) go: Arc<GlobalObject>,
+	/// セッション中のログに接続元・ホスト名・周波数を付ける
+	pub(crate) span: tracing::Span,
 }
 pub struct ClientMeta {
 	pub(crate) id: uuid::Uuid,
-	pub(crate) addr: std::net::SocketAddr,
 	pub hostname: String,
 	pub last_sync_time: i64,
+	pub(crate) stats: ClientStats,
+	pub(crate) sync_window: SyncWindow,
+	/// 送受信したバイト数 `Counted`が直接数える
+	bytes_in: Arc<AtomicU64>,
+	bytes_out: Arc<AtomicU64>,
+}
+impl ClientMeta {
+	fn updated(&self) -> Event {
+		Event::ClientUpdated {
+			id: self.id.to_string(),
+			name: self.hostname.clone(),
+			sync: self.last_sync_time,
+			stats: self.stats(),
+		}
+	}
+	pub(crate) fn stats(&self) -> ClientStats {
+		ClientStats {
+			bytes_in: self.bytes_in.load(Ordering::Relaxed),
+			bytes_out: self.bytes_out.load(Ordering::Relaxed),
+			sync: self.sync_window.summary(),
+			..self.stats.clone()
+		}
+	}
+}
+/// セッション開始からの累計
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ClientStats {
+	/// UNIXミリ秒
+	pub connected_at: i64,
+	/// 最後にコマンドを受け取った時刻
+	pub last_activity: i64,
+	/// 最後に設定された周波数
+	pub frequency: Option<String>,
+	/// コマンド名ごとの回数
+	pub commands: BTreeMap<&'static str, u64>,
+	pub bytes_in: u64,
+	pub bytes_out: u64,
+	pub sync: SyncSummary,
+	/// クライアントから搬入した量
+	pub inserted: Moved,
+	/// クライアントへ搬出した量
+	pub taken: Moved,
+}
+/// アイテム数・液体量・エネルギー量
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Moved {
+	pub items: i64,
+	pub fluid: i64,
+	pub energy: i64,
+}
+/// 直近の同期(PackStartからPackEndまで)の所要時間
+#[derive(Debug, Default)]
+pub struct SyncWindow {
+	/// (終了時刻, 所要時間) UNIXミリ秒とミリ秒
+	samples: VecDeque<(i64, i64)>,
+	/// 最後の同期が設定の閾値を超えた
+	slow: bool,
+}
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SyncSummary {
+	pub count: usize,
+	pub min: i64,
+	pub avg: i64,
+	pub p95: i64,
+	pub max: i64,
+	/// 1分あたりの同期回数
+	pub per_minute: f64,
+	pub slow: bool,
+}
+impl SyncWindow {
+	pub(crate) fn push(&mut self, end: i64, duration: i64, max_len: usize, slow: bool) {
+		self.samples.push_back((end, duration));
+		while self.samples.len() > max_len.max(1) {
+			self.samples.pop_front();
+		}
+		self.slow = slow;
+	}
+	pub(crate) fn summary(&self) -> SyncSummary {
+		let mut durations = self.samples.iter().map(|(_, d)| *d).collect::<Vec<_>>();
+		durations.sort_unstable();
+		let count = durations.len();
+		if count == 0 {
+			return SyncSummary::default();
+		}
+		let elapsed = self.samples[count - 1].0 - self.samples[0].0;
+		let per_minute = if elapsed > 0 {
+			(count - 1) as f64 * 60000.0 / elapsed as f64
+		} else {
+			0.0
+		};
+		SyncSummary {
+			count,
+			min: durations[0],
+			avg: durations.iter().sum::<i64>() / count as i64,
+			p95: durations[(count * 95).div_ceil(100) - 1],
+			max: durations[count - 1],
+			per_minute,
+			slow: self.slow,
+		}
+	}
+}
+impl ClientStats {
+	fn command(&mut self, command: i8) {
+		self.last_activity = chrono::Utc::now().timestamp_millis();
+		if let Some(name) = command_name(command) {
+			*self.commands.entry(name).or_default() += 1;
+		}
+	}
+	pub(crate) fn moved(&mut self, kind: Kind, direction: Direction, count: i64) {
+		let moved = match direction {
+			Direction::Insert => &mut self.inserted,
+			Direction::Take => &mut self.taken,
+		};
+		match kind {
+			Kind::Item => moved.items += count,
+			Kind::Fluid => moved.fluid += count,
+			Kind::Energy => moved.energy += count,
+		}
+	}
 }
 
 #[derive(FromPrimitive, ToPrimitive, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
@@ -41,16 +176,55 @@ enum Command {
 	SetHostName = 8,
 	PackStart = 9,
 	PackEnd = 10,
+	EnergyQuery = 11,
+	FluidQuery = 12,
+	ItemQuery = 13,
+}
+/// メトリクスのラベル用 未知のコマンドはNone
+pub(crate) fn command_name(command: i8) -> Option<&'static str> {
+	Some(match Command::from_i8(command)? {
+		Command::NOP => "NOP",
+		Command::SetFrequency => "SetFrequency",
+		Command::ItemFromClient => "ItemFromClient",
+		Command::ItemToClient => "ItemToClient",
+		Command::FluidFromClient => "FluidFromClient",
+		Command::FluidToClient => "FluidToClient",
+		Command::EnergyFromClient => "EnergyFromClient",
+		Command::EnergyToClient => "EnergyToClient",
+		Command::SetHostName => "SetHostName",
+		Command::PackStart => "PackStart",
+		Command::PackEnd => "PackEnd",
+		Command::EnergyQuery => "EnergyQuery",
+		Command::FluidQuery => "FluidQuery",
+		Command::ItemQuery => "ItemQuery",
+	})
 }
 
 impl ClientSession {
 	pub fn new(soc: TcpStream, addr: SocketAddr, go: Arc<GlobalObject>) -> Self {
 		let (reader, writer) = soc.into_split();
+		let (bytes_in, bytes_out) = Default::default();
+		let reader = Counted::new(reader, go.metrics.bytes_in.clone()).with_counter(&bytes_in);
+		let writer = Counted::new(writer, go.metrics.bytes_out.clone()).with_counter(&bytes_out);
+		let id = uuid::Uuid::new_v4();
+		let span = tracing::info_span!(
+			"session",
+			%id,
+			%addr,
+			hostname = tracing::field::Empty,
+			frequency = tracing::field::Empty,
+		);
 		let meta = Arc::new(Mutex::new(ClientMeta {
-			id: uuid::Uuid::new_v4(),
-			addr,
+			id,
 			hostname: "DefaultHostName".into(),
 			last_sync_time: 0,
+			stats: ClientStats {
+				connected_at: chrono::Utc::now().timestamp_millis(),
+				..Default::default()
+			},
+			sync_window: SyncWindow::default(),
+			bytes_in,
+			bytes_out,
 		}));
 		ClientSession {
 			reader,
@@ -59,38 +233,74 @@ impl ClientSession {
 			freq: None,
 			meta,
 			go,
+			span,
 		}
 	}
+	/// 切断したら接続中のクライアントから外すので、メトリクスなどにも残らない
 	pub async fn session(mut self) -> Result<(), tokio::io::Error> {
+		let id = self.meta.lock().await.id;
+		let result = self.commands().await;
+		if self.go.clients.write().await.remove(&id).is_some() {
+			self.go
+				.send_event(Event::ClientDisconnected { id: id.to_string() });
+		}
+		result
+	}
+	async fn commands(&mut self) -> Result<(), tokio::io::Error> {
 		self.writer.write_i64(CLIENT_VERSION).await?;
 		{
 			let mut clients = self.go.clients.write().await;
 			let id = self.meta.lock().await.id;
 			clients.insert(id, self.meta.clone());
-			println!(
-				"start session remote address {}",
-				self.meta.lock().await.addr
-			);
+			let meta = self.meta.lock().await;
+			tracing::debug!("start session");
+			self.go.send_event(Event::ClientConnected {
+				id: id.to_string(),
+				name: meta.hostname.clone(),
+			});
 		}
 		loop {
 			let command = self.reader.read_i8().await?;
+			self.go.metrics.command(command);
+			self.meta.lock().await.stats.command(command);
 			match Command::from_i8(command) {
 				Some(Command::NOP) => {
 					//NOP
 				}
 				Some(Command::SetHostName) => {
+					//読み込み中はロックしない
+					let hostname = read_string(&mut self.reader).await?;
 					let mut meta = self.meta.lock().await;
-					meta.hostname = read_string(&mut self.reader).await?;
+					meta.hostname = hostname;
+					self.span.record("hostname", meta.hostname.as_str());
+					self.go.send_event(meta.updated());
 				}
 				Some(Command::PackStart) => {
 					self.pack_start = chrono::Utc::now();
 				}
 				Some(Command::PackEnd) => {
+					let now = chrono::Utc::now();
+					let duration = (now - self.pack_start).num_milliseconds();
+					let (window, threshold) = {
+						let config = self.go.config.read().await;
+						(config.sync_window, config.slow_sync_millis)
+					};
+					let slow = threshold.is_some_and(|t| duration > t);
+					if slow {
+						tracing::warn!(duration, threshold, "slow sync");
+					}
 					let mut meta = self.meta.lock().await;
-					meta.last_sync_time = (chrono::Utc::now() - self.pack_start).num_milliseconds();
+					meta.last_sync_time = duration;
+					let window = window.unwrap_or(SYNC_WINDOW);
+					meta.sync_window
+						.push(now.timestamp_millis(), duration, window, slow);
+					self.go.send_event(meta.updated());
 				}
 				Some(Command::SetFrequency) => {
-					self.freq = Some(Frequency(read_string(&mut self.reader).await?));
+					let freq = read_string(&mut self.reader).await?;
+					self.span.record("frequency", freq.as_str());
+					self.meta.lock().await.stats.frequency = Some(freq.clone());
+					self.freq = Some(Frequency(freq));
 				}
 				Some(Command::EnergyToClient) => {
 					self.energy_send().await?;
@@ -110,9 +320,18 @@ impl ClientSession {
 				Some(Command::FluidFromClient) => {
 					self.fluid_recv().await?;
 				}
+				Some(Command::EnergyQuery) => {
+					self.energy_query().await?;
+				}
+				Some(Command::FluidQuery) => {
+					self.fluid_query().await?;
+				}
+				Some(Command::ItemQuery) => {
+					self.item_query().await?;
+				}
 				None => {
 					//謎
-					println!("unknown command {}", command);
+					tracing::warn!(command, "unknown command");
 					break;
 				}
 			}
@@ -123,3 +342,203 @@ impl ClientSession {
 		self.freq.as_ref().unwrap()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::{TcpListener, TcpStream},
+	};
+
+	use super::{ClientMeta, ClientSession, SyncWindow};
+	use crate::{
+		config::FrequencyConfig, fluid::FluidStack, item::ItemStack, write_string, Frequency,
+		GlobalObject,
+	};
+
+	/// セッションを開始し、ホスト名と周波数を設定したクライアント
+	async fn connect(
+		go: &Arc<GlobalObject>,
+		hostname: &str,
+		freq: &str,
+	) -> (TcpStream, Arc<tokio::sync::Mutex<ClientMeta>>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let mut client = TcpStream::connect(listener.local_addr().unwrap())
+			.await
+			.unwrap();
+		let (soc, addr) = listener.accept().await.unwrap();
+		let session = ClientSession::new(soc, addr, go.clone());
+		let meta = session.meta.clone();
+		tokio::spawn(session.session());
+		assert_eq!(client.read_i64().await.unwrap(), super::CLIENT_VERSION);
+		client.write_i8(8).await.unwrap();
+		write_string(&mut client, hostname).await.unwrap();
+		client.write_i8(1).await.unwrap();
+		write_string(&mut client, freq).await.unwrap();
+		(client, meta)
+	}
+	async fn take_energy(client: &mut TcpStream, max: i64) -> i64 {
+		client.write_i8(7).await.unwrap();
+		client.write_i64(max).await.unwrap();
+		client.read_i64().await.unwrap()
+	}
+	/// 受け取ったスタック数 NBTが別送されないスタックのみ
+	async fn take_items(client: &mut TcpStream, max_stacks: i32) -> i32 {
+		client.write_i8(3).await.unwrap();
+		client.write_i32(max_stacks).await.unwrap();
+		let len = client.read_i32().await.unwrap();
+		let mut gz = vec![0u8; len as usize];
+		client.read_exact(&mut gz).await.unwrap();
+		let mut r = async_compression::tokio::bufread::GzipDecoder::new(&gz[..]);
+		r.read_i32().await.unwrap()
+	}
+	async fn take_fluid(client: &mut TcpStream, name: &str, max: i64) -> i64 {
+		client.write_i8(5).await.unwrap();
+		let fs = FluidStack::new(name.into(), max, None);
+		fs.write(client).await.unwrap();
+		let len = client.read_i32().await.unwrap();
+		if len == 0 {
+			return 0;
+		}
+		let mut data = vec![0u8; len as usize];
+		client.read_exact(&mut data).await.unwrap();
+		FluidStack::read(&mut &data[..]).await.unwrap().count
+	}
+
+	#[test]
+	fn session_stats() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::dummy().await);
+				let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+				let mut client = TcpStream::connect(listener.local_addr().unwrap())
+					.await
+					.unwrap();
+				let (soc, addr) = listener.accept().await.unwrap();
+				let session = ClientSession::new(soc, addr, go.clone());
+				let meta = session.meta.clone();
+				let session = tokio::spawn(session.session());
+				assert_eq!(client.read_i64().await.unwrap(), super::CLIENT_VERSION);
+				client.write_i8(8).await.unwrap();
+				write_string(&mut client, "host").await.unwrap();
+				client.write_i8(1).await.unwrap();
+				write_string(&mut client, "RED").await.unwrap();
+				//EnergyFromClient
+				client.write_i8(6).await.unwrap();
+				client.write_i64(1000).await.unwrap();
+				assert_eq!(client.read_i64().await.unwrap(), 0);
+				drop(client);
+				assert!(session.await.unwrap().is_err());
+				let stats = meta.lock().await.stats();
+				assert_eq!(stats.frequency.as_deref(), Some("RED"));
+				assert_eq!(stats.commands["SetHostName"], 1);
+				assert_eq!(stats.commands["EnergyFromClient"], 1);
+				assert_eq!(stats.inserted.energy, 1000);
+				assert_eq!(stats.taken.energy, 0);
+				//ホスト名 + 周波数 + コマンド3個 + エネルギー量
+				assert_eq!(stats.bytes_in, 2 + 4 + 2 + 3 + 3 + 8);
+				assert_eq!(stats.bytes_out, 8 + 8);
+				assert!(stats.last_activity >= stats.connected_at);
+			});
+	}
+	#[test]
+	fn fair_share() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::new(Default::default()));
+				{
+					let mut config = go.config.write().await;
+					let fair = FrequencyConfig {
+						fair_share: true,
+						..Default::default()
+					};
+					config.frequencies.insert("FAIR".into(), fair);
+					config.client_weights.insert("a".into(), 3);
+				}
+				let freq = Frequency("FAIR".into());
+				go.energy(&freq).merge(1000);
+				let mut stacks = vec![ItemStack::dummy(); 8];
+				go.items(&freq).insert_items(&mut stacks).await;
+				let lava = FluidStack::new("lava".into(), 1000, None);
+				go.fluids(&freq).insert_fluid(lava).await;
+				let (mut a, _) = connect(&go, "a", "FAIR").await;
+				let (mut b, b_meta) = connect(&go, "b", "FAIR").await;
+				//搬出を要求した時点で受け取り手として数える
+				assert_eq!(take_energy(&mut a, 0).await, 0);
+				assert_eq!(take_energy(&mut b, 0).await, 0);
+				//搬出開始時の量を重み3:1で分ける 先に受け取った側も自分の分以上は受け取れない
+				assert_eq!(take_energy(&mut a, 1000).await, 750);
+				assert_eq!(take_energy(&mut a, 1000).await, 0);
+				assert_eq!(take_energy(&mut b, 1000).await, 250);
+				assert_eq!(take_items(&mut a, 100).await, 6);
+				assert_eq!(take_items(&mut b, 100).await, 2);
+				assert_eq!(take_fluid(&mut a, "lava", 1000).await, 750);
+				assert_eq!(take_fluid(&mut b, "lava", 1000).await, 250);
+				//期間中の搬入も同じ割合で分ける
+				go.energy(&freq).merge(400);
+				assert_eq!(take_energy(&mut b, 1000).await, 100);
+				assert_eq!(take_energy(&mut a, 1000).await, 300);
+				//しばらく搬出していないクライアントは数えない
+				let b_id = b_meta.lock().await.id;
+				go.fair_shares.expire(&freq, &b_id);
+				go.energy(&freq).merge(1000);
+				assert_eq!(take_energy(&mut a, 1000).await, 1000);
+			});
+	}
+	#[test]
+	fn query_commands() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::dummy().await);
+				let freq = Frequency("RED, RED, RED".into());
+				go.energy(&freq).merge(1000);
+				let (mut client, _) = connect(&go, "query", &freq.0).await;
+				let items_before = go.items(&freq).to_vec().await;
+				let fluids_before = go.fluids(&freq).to_vec().await;
+				//何度問い合わせても中身は変わらない
+				for _ in 0..2 {
+					client.write_i8(11).await.unwrap();
+					assert_eq!(client.read_i64().await.unwrap(), 1000);
+					client.write_i8(12).await.unwrap();
+					assert_eq!(client.read_i32().await.unwrap(), 1);
+					let fs = FluidStack::read(&mut client).await.unwrap();
+					assert_eq!(fs, FluidStack::dummy());
+					client.write_i8(13).await.unwrap();
+					assert_eq!(client.read_i32().await.unwrap(), 1);
+					assert_eq!(client.read_i32().await.unwrap(), 99);
+				}
+				assert_eq!(go.energy_value(&freq), 1000);
+				assert_eq!(go.items(&freq).to_vec().await, items_before);
+				assert_eq!(go.fluids(&freq).to_vec().await, fluids_before);
+			});
+	}
+	#[test]
+	fn sync_summary() {
+		let mut window = SyncWindow::default();
+		assert_eq!(window.summary().count, 0);
+		for i in 1..=30 {
+			window.push(i * 1000, i, 20, i == 30);
+		}
+		let summary = window.summary();
+		//直近20回(11..=30)
+		assert_eq!(summary.count, 20);
+		assert_eq!(summary.min, 11);
+		assert_eq!(summary.max, 30);
+		assert_eq!(summary.avg, 20);
+		assert_eq!(summary.p95, 29);
+		//1秒に1回
+		assert_eq!(summary.per_minute, 60.0);
+		assert!(summary.slow);
+	}
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::{energy::EnergyOverflow, item::ItemOrder, logging::LogFormat, Frequency};
+
+pub const CONFIG_FILE: &str = "config.json";
+
+/// 管理者設定(config.json)
+///
+/// ```json
+/// {
+///     "frequencies": {
+///         "RED, RED, RED": { "item_order": "lifo" },
+///         "WHITE, BLUE, WHITE": { "item_order": { "priority": ["minecraft:diamond*", "*:nether_star"] } },
+///         "BLACK, BLACK, BLACK": { "fair_share": true },
+///         "WHITE, WHITE, WHITE": { "energy_capacity": 1000000, "energy_overflow": { "spill": "RED, WHITE, RED" } },
+///         "RED, WHITE, RED": { "energy_loss_percent": 5.0, "energy_max_input": 10000, "energy_max_output": 8000 },
+///         "BLUE, BLUE, BLUE": { "ttl_secs": 86400, "expire_to": "BLACK, WHITE, BLACK" }
+///     },
+///     "energy_rate_window_millis": 50,
+///     "empty_frequency_grace_secs": 300,
+///     "api_token": "change-me",
+///     "admin_token": "change-me-too",
+///     "history_interval_secs": 60,
+///     "history_len": 1440,
+///     "history_file": "history.json",
+///     "audit_file": "audit.log",
+///     "audit_max_bytes": 10485760,
+///     "audit_max_files": 5,
+///     "autoload": true,
+///     "autosave_interval_secs": 300,
+///     "sync_window": 100,
+///     "slow_sync_millis": 1000,
+///     "log_level": "info,FedStorageServer_rs=debug",
+///     "log_format": "json",
+///     "client_weights": { "survival": 3, "creative": 1 }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+	pub frequencies: HashMap<String, FrequencyConfig>,
+	/// 公平分配モードでのホスト名ごとの重み 未指定は1
+	pub client_weights: HashMap<String, u32>,
+	/// エネルギーの搬入出レート制限の集計期間 未指定は1tick
+	pub energy_rate_window_millis: Option<i64>,
+	/// 空になった周波数を削除するまでの猶予 未指定は300秒
+	pub empty_frequency_grace_secs: Option<u64>,
+	/// HTTPから搬入出する時の`Authorization: Bearer`トークン 未指定は搬入出できない
+	pub api_token: Option<String>,
+	/// HTTPから保存・停止などの管理操作をする時のトークン 未指定は操作できない
+	pub admin_token: Option<String>,
+	/// 残量を記録する間隔 未指定は60秒
+	pub history_interval_secs: Option<u64>,
+	/// 残しておく記録の数 未指定は1440
+	pub history_len: Option<usize>,
+	/// 記録を保存するファイル 未指定は保存しない
+	pub history_file: Option<String>,
+	/// 搬入出の監査ログ 未指定はaudit.log
+	pub audit_file: Option<String>,
+	/// 監査ログをこの大きさで古いファイルに回す 未指定は10MiB
+	pub audit_max_bytes: Option<u64>,
+	/// 残しておく古い監査ログの数 未指定は5
+	pub audit_max_files: Option<usize>,
+	/// 起動時にsave.dat.gzを読み込む
+	pub autoload: bool,
+	/// 自動保存の間隔 未指定は自動保存しない
+	pub autosave_interval_secs: Option<u64>,
+	/// 同期時間の統計に使う直近の回数 未指定は100
+	pub sync_window: Option<usize>,
+	/// 同期がこれより長いと警告する 未指定は警告しない
+	pub slow_sync_millis: Option<i64>,
+	/// `RUST_LOG`と同じ書式 未指定は`RUST_LOG`かinfo 再読み込みで反映
+	pub log_level: Option<String>,
+	/// 未指定はtext
+	pub log_format: LogFormat,
+}
+/// 周波数ごとの設定 未指定の周波数は既定値
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FrequencyConfig {
+	pub item_order: ItemOrder,
+	/// 最近搬出したクライアント間でアイテム・液体・エネルギーを重みに応じて分配する
+	pub fair_share: bool,
+	/// 未指定はu32::MAX
+	pub energy_capacity: Option<i64>,
+	pub energy_overflow: EnergyOverflow,
+	/// 搬入時に失われる割合
+	pub energy_loss_percent: f64,
+	/// 集計期間ごとの最大搬入量
+	pub energy_max_input: Option<i64>,
+	/// 集計期間ごとの最大搬出量
+	pub energy_max_output: Option<i64>,
+	/// 搬入から期限切れになるまでの秒数 未指定は無期限
+	pub ttl_secs: Option<u64>,
+	/// 期限切れのアイテム・液体の移動先 未指定は削除
+	pub expire_to: Option<String>,
+}
+impl Config {
+	/// ファイルが無い場合は既定値
+	pub async fn load_file(path: &str) -> Result<Self, tokio::io::Error> {
+		let data = match tokio::fs::read(path).await {
+			Ok(data) => data,
+			Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => return Ok(Self::default()),
+			Err(e) => return Err(e),
+		};
+		//serdeのエラーは行と列を含む
+		serde_json::from_slice(&data)
+			.map_err(|e| tokio::io::Error::other(format!("{}: {}", path, e)))
+	}
+	pub fn frequency(&self, freq: &Frequency) -> FrequencyConfig {
+		self.frequencies.get(&freq.0).cloned().unwrap_or_default()
+	}
+	pub fn client_weight(&self, hostname: &str) -> u32 {
+		self.client_weights.get(hostname).copied().unwrap_or(1)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Config;
+	use crate::{item::ItemOrder, Frequency};
+
+	#[test]
+	fn parse_config() {
+		let json = r#"{
+			"frequencies": {
+				"RED, RED, RED": { "item_order": "round_robin" },
+				"WHITE, BLUE, WHITE": { "item_order": { "priority": ["minecraft:diamond*"] } }
+			},
+			"client_weights": { "survival": 3 }
+		}"#;
+		let config: Config = serde_json::from_str(json).unwrap();
+		assert_eq!(
+			config
+				.frequency(&Frequency("RED, RED, RED".into()))
+				.item_order,
+			ItemOrder::RoundRobin
+		);
+		assert_eq!(
+			config
+				.frequency(&Frequency("WHITE, BLUE, WHITE".into()))
+				.item_order,
+			ItemOrder::Priority(vec!["minecraft:diamond*".into()])
+		);
+		assert_eq!(
+			config.frequency(&Frequency("BLACK".into())).item_order,
+			ItemOrder::Fifo
+		);
+		assert_eq!(config.client_weight("survival"), 3);
+		assert_eq!(config.client_weight("creative"), 1);
+	}
+	#[test]
+	fn config_error() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let dir = std::env::temp_dir().join(format!("config-{}", uuid::Uuid::new_v4()));
+				tokio::fs::create_dir_all(&dir).await.unwrap();
+				let path = dir.join("config.json").to_string_lossy().into_owned();
+				tokio::fs::write(&path, "{\n\t\"autoload\": yes\n}")
+					.await
+					.unwrap();
+				//どのファイルの何行目かが分かる
+				let e = Config::load_file(&path).await.unwrap_err().to_string();
+				assert!(e.starts_with(&path), "{}", e);
+				assert!(e.contains("line 2"), "{}", e);
+				tokio::fs::remove_dir_all(&dir).await.unwrap();
+			});
+	}
+}
@@ -1,40 +1,366 @@
+use std::sync::{
+	atomic::{AtomicI64, Ordering},
+	Arc, Mutex, MutexGuard,
+};
+
+use serde::Deserialize;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::client::ClientSession;
+use crate::{
+	audit::{Direction, Kind},
+	client::ClientSession,
+	config::Config,
+	events::Notifier,
+	fair::Resource,
+	Frequency, GlobalObject,
+};
 
 const ENERGY_BUFFER_LIMIT: i64 = u32::MAX as i64;
 
+/// 容量を超えた分の扱い
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnergyOverflow {
+	/// クライアントに返す
+	#[default]
+	Reject,
+	/// 捨てる
+	Void,
+	/// 指定した周波数に流す 流し先でも溢れた分はクライアントに返す
+	Spill(String),
+}
+pub(crate) fn energy_capacity(config: &Config, freq: &Frequency) -> i64 {
+	config
+		.frequency(freq)
+		.energy_capacity
+		.unwrap_or(ENERGY_BUFFER_LIMIT)
+}
+/// 1tick
+const ENERGY_RATE_WINDOW_MILLIS: i64 = 50;
+
+/// 周波数ごとのエネルギーバッファ
+///
+/// 値は原子的に更新するので、異なる周波数同士はロックを奪い合わない
+#[derive(Debug)]
+pub(crate) struct Energy {
+	value: AtomicI64,
+	/// 搬入時に失われた累計
+	loss: AtomicI64,
+	/// レート制限が設定された周波数でのみ使う
+	window: Mutex<EnergyWindow>,
+	notifier: Option<Notifier>,
+}
+#[derive(Debug)]
+struct EnergyWindow {
+	start: chrono::DateTime<chrono::Utc>,
+	input: i64,
+	output: i64,
+}
+impl Energy {
+	pub(crate) fn new(value: i64) -> Self {
+		Self {
+			value: AtomicI64::new(value),
+			loss: AtomicI64::new(0),
+			window: Mutex::new(EnergyWindow {
+				start: chrono::Utc::now(),
+				input: 0,
+				output: 0,
+			}),
+			notifier: None,
+		}
+	}
+	pub(crate) fn with_notifier(mut self, notifier: Notifier) -> Self {
+		self.notifier = Some(notifier);
+		self
+	}
+	fn changed(&self) {
+		if let Some(notifier) = &self.notifier {
+			notifier.energy(self.value(), self.loss());
+		}
+	}
+	pub(crate) fn value(&self) -> i64 {
+		self.value.load(Ordering::Acquire)
+	}
+	pub(crate) fn loss(&self) -> i64 {
+		self.loss.load(Ordering::Acquire)
+	}
+	/// 容量まで加算し、加算できなかった量を返す
+	pub(crate) fn add(&self, raw_recv: i64, capacity: i64) -> i64 {
+		let mut reject = 0;
+		let _ = self
+			.value
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |old_energy| {
+				let target_recv = 0.max(capacity - old_energy).min(raw_recv);
+				reject = 0.max(raw_recv - target_recv);
+				Some(old_energy + target_recv)
+			});
+		if reject < raw_recv {
+			self.changed();
+		}
+		reject
+	}
+	/// 最大`max_send`を減算し、減算した量を返す
+	pub(crate) fn sub(&self, max_send: i64) -> i64 {
+		let mut send = 0;
+		let _ = self
+			.value
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |old_energy| {
+				send = max_send.min(old_energy);
+				Some(old_energy - send)
+			});
+		if send != 0 {
+			self.changed();
+		}
+		send
+	}
+	/// 容量を無視して加算する
+	pub(crate) fn merge(&self, value: i64) {
+		let _ = self
+			.value
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |old_energy| {
+				Some(old_energy.saturating_add(value))
+			});
+		self.changed();
+	}
+	/// 集計期間が過ぎていれば搬入出量をリセットしてロックを返す
+	fn window(&self, config: &Config) -> MutexGuard<'_, EnergyWindow> {
+		let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+		let window_millis = config
+			.energy_rate_window_millis
+			.unwrap_or(ENERGY_RATE_WINDOW_MILLIS);
+		let now = chrono::Utc::now();
+		if (now - window.start).num_milliseconds() >= window_millis {
+			window.start = now;
+			window.input = 0;
+			window.output = 0;
+		}
+		window
+	}
+}
+impl GlobalObject {
+	pub(crate) fn energy(&self, freq: &Frequency) -> Arc<Energy> {
+		self.energy_buffers.get_or_insert_with(freq, || {
+			Arc::new(Energy::new(0).with_notifier(self.notifier(freq)))
+		})
+	}
+	/// 容量・レート制限・損失・溢れ時の設定に従って搬入し、受け入れなかった量を返す
+	pub(crate) async fn energy_insert(&self, freq: &Frequency, raw_recv: i64) -> i64 {
+		let config = self.config.read().await;
+		let freq_config = config.frequency(freq);
+		let energy = self.energy(freq);
+		let mut window = freq_config
+			.energy_max_input
+			.map(|max_input| (max_input, energy.window(&config)));
+		let offer = match &window {
+			Some((max_input, window)) => raw_recv.min(0.max(max_input - window.input)),
+			None => raw_recv,
+		};
+		let loss = if offer > 0 {
+			((offer as f64 * freq_config.energy_loss_percent / 100.0) as i64).clamp(0, offer)
+		} else {
+			0
+		};
+		let net_recv = offer - loss;
+		let mut net_reject = energy.add(net_recv, energy_capacity(&config, freq));
+		if net_reject > 0 {
+			net_reject = match freq_config.energy_overflow {
+				EnergyOverflow::Reject => net_reject,
+				EnergyOverflow::Void => 0,
+				EnergyOverflow::Spill(target) => {
+					let target = Frequency(target);
+					let capacity = energy_capacity(&config, &target);
+					self.energy(&target).add(net_reject, capacity)
+				}
+			};
+		}
+		//受け入れなかった分の損失は返す
+		let refund = if net_reject > 0 {
+			(loss as i128 * net_reject as i128 / net_recv as i128) as i64
+		} else {
+			0
+		};
+		let reject = (raw_recv - offer) + net_reject + refund;
+		self.metrics.rejected_energy(reject);
+		if let Some((_, window)) = &mut window {
+			window.input += raw_recv - reject;
+		}
+		if loss - refund != 0 {
+			energy.loss.fetch_add(loss - refund, Ordering::AcqRel);
+			energy.changed();
+		}
+		reject
+	}
+	/// レート制限に従って最大`max_send`を搬出し、搬出した量を返す
+	pub(crate) async fn energy_take(&self, freq: &Frequency, max_send: i64) -> i64 {
+		let config = self.config.read().await;
+		let Some(energy) = self.energy_buffers.get(freq) else {
+			return 0;
+		};
+		match config.frequency(freq).energy_max_output {
+			Some(max_output) => {
+				let mut window = energy.window(&config);
+				let send = energy.sub(max_send.min(0.max(max_output - window.output)));
+				window.output += send;
+				send
+			}
+			None => energy.sub(max_send),
+		}
+	}
+	pub(crate) fn energy_value(&self, freq: &Frequency) -> i64 {
+		self.energy_buffers
+			.get(freq)
+			.map(|energy| energy.value())
+			.unwrap_or(0)
+	}
+}
+
 impl ClientSession {
 	pub(crate) async fn energy_recv(&mut self) -> Result<(), tokio::io::Error> {
 		let raw_recv = self.reader.read_i64().await?;
-		let reject = {
-			let mut lock = self.go.energy_buffers.write().await;
-			let old_energy = lock.remove(self.freq());
-			let old_energy = old_energy.unwrap_or(0);
-			let target_recv = 0.max(ENERGY_BUFFER_LIMIT - old_energy).min(raw_recv);
-			let reject = 0.max(raw_recv - target_recv);
-			let new_energy = old_energy + target_recv;
-			lock.insert(self.freq().clone(), new_energy);
-			reject
-		};
+		let reject = self.go.energy_insert(self.freq(), raw_recv).await;
+		self.audit(Kind::Energy, Direction::Insert, None, raw_recv - reject)
+			.await;
 		self.writer.write_i64(reject).await?;
 		Ok(())
 	}
 	pub(crate) async fn energy_send(&mut self) -> Result<(), tokio::io::Error> {
 		let max_send = self.reader.read_i64().await?;
 		let max_send = max_send.max(0);
-		let send = {
-			let mut lock = self.go.energy_buffers.write().await;
-			let old_energy = lock.remove(self.freq());
-			let old_energy = old_energy.unwrap_or(0);
-			let target_send = max_send.min(old_energy);
-			let new_energy = old_energy - target_send;
-			if new_energy > 0 {
-				lock.insert(self.freq().clone(), new_energy);
-			}
-			target_send
-		};
+		let available = self.go.energy_value(self.freq());
+		let max_send = max_send.min(self.fair_share(Resource::Energy, available).await);
+		let send = self.go.energy_take(self.freq(), max_send).await;
+		self.fair_took(&Resource::Energy, send).await;
+		self.audit(Kind::Energy, Direction::Take, None, send).await;
 		self.writer.write_i64(send).await?;
 		Ok(())
 	}
+	/// 搬入出せずに現在のエネルギー量を返す
+	pub(crate) async fn energy_query(&mut self) -> Result<(), tokio::io::Error> {
+		let value = self.go.energy_value(self.freq());
+		self.writer.write_i64(value).await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{config::Config, Frequency, GlobalObject};
+
+	impl GlobalObject {
+		fn energy_loss(&self, freq: &Frequency) -> i64 {
+			self.energy_buffers
+				.get(freq)
+				.map(|energy| energy.loss())
+				.unwrap_or(0)
+		}
+	}
+
+	#[test]
+	fn energy_overflow() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let json = r#"{
+					"frequencies": {
+						"REJECT": { "energy_capacity": 100 },
+						"VOID": { "energy_capacity": 100, "energy_overflow": "void" },
+						"SPILL": { "energy_capacity": 100, "energy_overflow": { "spill": "OVERFLOW" } },
+						"OVERFLOW": { "energy_capacity": 50 }
+					}
+				}"#;
+				let config: Config = serde_json::from_str(json).unwrap();
+				let go = GlobalObject::new(config);
+				let freq = |s: &str| Frequency(s.into());
+				assert_eq!(go.energy_insert(&freq("REJECT"), 80).await, 0);
+				assert_eq!(go.energy_insert(&freq("REJECT"), 80).await, 60);
+				assert_eq!(go.energy_value(&freq("REJECT")), 100);
+				assert_eq!(go.energy_insert(&freq("VOID"), 180).await, 0);
+				assert_eq!(go.energy_value(&freq("VOID")), 100);
+				assert_eq!(go.energy_insert(&freq("SPILL"), 180).await, 30);
+				assert_eq!(go.energy_value(&freq("SPILL")), 100);
+				assert_eq!(go.energy_value(&freq("OVERFLOW")), 50);
+				assert_eq!(go.energy_take(&freq("SPILL"), 30).await, 30);
+				assert_eq!(go.energy_take(&freq("SPILL"), 300).await, 70);
+				assert_eq!(go.energy_value(&freq("SPILL")), 0);
+			});
+	}
+	#[test]
+	fn energy_loss_and_rate() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let json = r#"{
+					"frequencies": {
+						"LOSS": { "energy_loss_percent": 10.0, "energy_max_input": 100, "energy_max_output": 40 },
+						"FULL": { "energy_capacity": 9, "energy_loss_percent": 10.0 }
+					},
+					"energy_rate_window_millis": 60000
+				}"#;
+				let config: Config = serde_json::from_str(json).unwrap();
+				let go = GlobalObject::new(config);
+				let freq = |s: &str| Frequency(s.into());
+				assert_eq!(go.energy_insert(&freq("LOSS"), 50).await, 0);
+				assert_eq!(go.energy_value(&freq("LOSS")), 45);
+				assert_eq!(go.energy_insert(&freq("LOSS"), 80).await, 30);
+				assert_eq!(go.energy_insert(&freq("LOSS"), 10).await, 10);
+				assert_eq!(go.energy_value(&freq("LOSS")), 90);
+				assert_eq!(go.energy_loss(&freq("LOSS")), 10);
+				assert_eq!(go.energy_take(&freq("LOSS"), 100).await, 40);
+				assert_eq!(go.energy_take(&freq("LOSS"), 100).await, 0);
+				assert_eq!(go.energy_insert(&freq("FULL"), 20).await, 10);
+				assert_eq!(go.energy_value(&freq("FULL")), 9);
+				assert_eq!(go.energy_loss(&freq("FULL")), 1);
+			});
+	}
+	/// 多数のセッションが同時に搬入出する際のスループット 別々の周波数と1つの共有周波数で測る
+	///
+	/// `cargo test --release energy_throughput -- --ignored --nocapture`
+	#[test]
+	#[ignore]
+	fn energy_throughput() {
+		const SESSIONS: usize = 64;
+		const OPS: usize = 20000;
+		let rt = tokio::runtime::Builder::new_multi_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		rt.block_on(async {
+			let go = std::sync::Arc::new(GlobalObject::new(Config::default()));
+			let run = |shared: bool| {
+				let go = go.clone();
+				async move {
+					let start = std::time::Instant::now();
+					let jobs = (0..SESSIONS).map(|i| {
+						let go = go.clone();
+						let freq = match shared {
+							true => Frequency("SHARED".into()),
+							false => Frequency(format!("F{}", i)),
+						};
+						tokio::spawn(async move {
+							for _ in 0..OPS {
+								go.energy_insert(&freq, 10).await;
+								go.energy_take(&freq, 10).await;
+							}
+						})
+					});
+					futures::future::join_all(jobs).await;
+					(SESSIONS * OPS * 2) as f64 / start.elapsed().as_secs_f64()
+				}
+			};
+			let distinct = run(false).await;
+			let shared = run(true).await;
+			println!(
+				"{} sessions: distinct frequencies {:.0} ops/s, shared frequency {:.0} ops/s",
+				SESSIONS, distinct, shared
+			);
+			for i in 0..SESSIONS {
+				assert_eq!(go.energy_value(&Frequency(format!("F{}", i))), 0);
+			}
+			assert_eq!(go.energy_value(&Frequency("SHARED".into())), 0);
+		});
+	}
 }
@@ -0,0 +1,107 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{client::ClientStats, Frequency, GlobalObject};
+
+/// 受信が追いつかない購読者はこれ以上遅れると取りこぼす
+const EVENT_CAPACITY: usize = 1024;
+
+/// ダッシュボードへ送る変更通知
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+	ItemFrequency {
+		id: String,
+		size: usize,
+	},
+	FluidFrequency {
+		id: String,
+		size: usize,
+	},
+	Energy {
+		id: String,
+		value: i64,
+		loss: i64,
+	},
+	/// GCで周波数が削除された `kind`はitem/fluid/energy
+	FrequencyRemoved {
+		kind: &'static str,
+		id: String,
+	},
+	ClientConnected {
+		id: String,
+		name: String,
+	},
+	ClientUpdated {
+		id: String,
+		name: String,
+		sync: i64,
+		stats: ClientStats,
+	},
+	ClientDisconnected {
+		id: String,
+	},
+}
+impl Event {
+	/// 同じキーの通知は最新のものだけ送れば良い
+	pub fn key(&self) -> (&'static str, &str) {
+		match self {
+			Event::ItemFrequency { id, .. } => ("item", id),
+			Event::FluidFrequency { id, .. } => ("fluid", id),
+			Event::Energy { id, .. } => ("energy", id),
+			Event::FrequencyRemoved { kind, id } => (kind, id),
+			Event::ClientConnected { id, .. }
+			| Event::ClientUpdated { id, .. }
+			| Event::ClientDisconnected { id } => ("client", id),
+		}
+	}
+}
+pub fn channel() -> broadcast::Sender<Event> {
+	broadcast::channel(EVENT_CAPACITY).0
+}
+/// 周波数のバッファが自分の変更を通知するために持つ
+#[derive(Clone, Debug)]
+pub struct Notifier {
+	freq: Frequency,
+	tx: broadcast::Sender<Event>,
+}
+impl Notifier {
+	pub fn items(&self, size: usize) {
+		self.send(Event::ItemFrequency {
+			id: self.freq.0.clone(),
+			size,
+		});
+	}
+	pub fn fluids(&self, size: usize) {
+		self.send(Event::FluidFrequency {
+			id: self.freq.0.clone(),
+			size,
+		});
+	}
+	pub fn energy(&self, value: i64, loss: i64) {
+		self.send(Event::Energy {
+			id: self.freq.0.clone(),
+			value,
+			loss,
+		});
+	}
+	fn send(&self, event: Event) {
+		//購読者がいなければ捨てる
+		if self.tx.receiver_count() > 0 {
+			let _ = self.tx.send(event);
+		}
+	}
+}
+impl GlobalObject {
+	pub(crate) fn notifier(&self, freq: &Frequency) -> Notifier {
+		Notifier {
+			freq: freq.clone(),
+			tx: self.events.clone(),
+		}
+	}
+	pub(crate) fn send_event(&self, event: Event) {
+		if self.events.receiver_count() > 0 {
+			let _ = self.events.send(event);
+		}
+	}
+}
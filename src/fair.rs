@@ -0,0 +1,145 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{client::ClientSession, fluid::FluidId, Frequency};
+
+/// 公平分配で搬出中とみなす期間 配分もこの期間ごとにやり直す
+pub(crate) const ACTIVE_RECEIVER_MILLIS: i64 = 5000;
+
+/// 公平分配の対象 液体は種類ごとに分ける
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub(crate) enum Resource {
+	Item,
+	Fluid(FluidId),
+	Energy,
+}
+/// 周波数ごとの受け取り手と配分
+///
+/// セッションの`ClientMeta`とは別に持ち、awaitを跨いでロックしない
+#[derive(Debug, Default)]
+pub struct FairShares(Mutex<HashMap<Frequency, Receivers>>);
+#[derive(Debug, Default)]
+struct Receivers {
+	/// セッションIDごとの(重み, 最終搬出時刻)
+	active: HashMap<uuid::Uuid, (u32, i64)>,
+	allotments: HashMap<Resource, Allotment>,
+}
+/// 1期間の配分
+#[derive(Debug)]
+struct Allotment {
+	/// UNIXミリ秒
+	started: i64,
+	/// 期間の開始時の量に期間中の増減を足したもの これを重みで分ける
+	total: i64,
+	/// 期間中にセッションが受け取った量
+	taken: HashMap<uuid::Uuid, i64>,
+}
+impl Allotment {
+	fn new(now: i64, available: i64) -> Self {
+		Self {
+			started: now,
+			total: available,
+			taken: HashMap::new(),
+		}
+	}
+	/// 端数は切り上げて搬出が止まらないようにする
+	fn quota(&self, weight: u32, total_weight: u32) -> i64 {
+		let share = (self.total.max(0) as i128 * weight as i128 + total_weight as i128 - 1)
+			/ total_weight as i128;
+		share as i64
+	}
+	fn taken(&self, id: &uuid::Uuid) -> i64 {
+		self.taken.get(id).copied().unwrap_or(0)
+	}
+}
+impl FairShares {
+	fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Frequency, Receivers>> {
+		self.0.lock().unwrap_or_else(|e| e.into_inner())
+	}
+	/// 搬出を記録し、`available`のうち`id`がこの期間に受け取れる残りを返す
+	pub(crate) fn share(
+		&self,
+		freq: &Frequency,
+		resource: Resource,
+		id: uuid::Uuid,
+		weight: u32,
+		available: i64,
+	) -> i64 {
+		let now = chrono::Utc::now().timestamp_millis();
+		let available = available.max(0);
+		let mut shares = self.lock();
+		let receivers = shares.entry(freq.clone()).or_default();
+		receivers.active.insert(id, (weight, now));
+		receivers
+			.active
+			.retain(|_, (_, last)| now - *last <= ACTIVE_RECEIVER_MILLIS);
+		let total_weight = receivers.active.values().map(|(w, _)| *w).sum::<u32>();
+		let allotment = receivers
+			.allotments
+			.entry(resource)
+			.or_insert_with(|| Allotment::new(now, available));
+		//期間中の搬入や他の経路での搬出は配分の対象に反映する
+		let remaining = allotment.total - allotment.taken.values().sum::<i64>();
+		allotment.total = allotment
+			.total
+			.saturating_add(available.saturating_sub(remaining));
+		let exhausted = receivers
+			.active
+			.iter()
+			.all(|(id, (weight, _))| allotment.taken(id) >= allotment.quota(*weight, total_weight));
+		if exhausted || now - allotment.started > ACTIVE_RECEIVER_MILLIS {
+			*allotment = Allotment::new(now, available);
+		}
+		let quota = allotment.quota(weight, total_weight) - allotment.taken(&id);
+		quota.clamp(0, available)
+	}
+	/// 実際に受け取った量を記録する
+	pub(crate) fn took(&self, freq: &Frequency, resource: &Resource, id: uuid::Uuid, amount: i64) {
+		let mut shares = self.lock();
+		let allotment = shares
+			.get_mut(freq)
+			.and_then(|receivers| receivers.allotments.get_mut(resource));
+		if let Some(allotment) = allotment {
+			*allotment.taken.entry(id).or_default() += amount;
+		}
+	}
+	/// 受け取り手のいなくなった周波数を削除する
+	pub(crate) fn purge(&self) {
+		let now = chrono::Utc::now().timestamp_millis();
+		self.lock().retain(|_, receivers| {
+			receivers
+				.active
+				.values()
+				.any(|(_, last)| now - *last <= ACTIVE_RECEIVER_MILLIS)
+		});
+	}
+	#[cfg(test)]
+	pub(crate) fn expire(&self, freq: &Frequency, id: &uuid::Uuid) {
+		if let Some(receivers) = self.lock().get_mut(freq) {
+			if let Some((_, last)) = receivers.active.get_mut(id) {
+				*last -= ACTIVE_RECEIVER_MILLIS + 1000;
+			}
+		}
+	}
+}
+impl ClientSession {
+	/// このクライアントが`available`のうち受け取れる量を返す 受け取った量は`fair_took`で記録する
+	pub(crate) async fn fair_share(&self, resource: Resource, available: i64) -> i64 {
+		let (id, hostname) = {
+			let meta = self.meta.lock().await;
+			(meta.id, meta.hostname.clone())
+		};
+		let weight = {
+			let config = self.go.config.read().await;
+			if !config.frequency(self.freq()).fair_share {
+				return available;
+			}
+			config.client_weight(&hostname)
+		};
+		let shares = &self.go.fair_shares;
+		shares.share(self.freq(), resource, id, weight.max(1), available)
+	}
+	pub(crate) async fn fair_took(&self, resource: &Resource, amount: i64) {
+		let id = self.meta.lock().await.id;
+		self.go.fair_shares.took(self.freq(), resource, id, amount);
+	}
+}
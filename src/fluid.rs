@@ -5,17 +5,35 @@ use tokio::{
 	sync::RwLock,
 };
 
-use crate::{read_string, to_hex_string, write_string, ClientSession};
+use crate::{
+	audit::{Direction, Kind},
+	events::Notifier,
+	fair::Resource,
+	meta::StackMeta,
+	read_string, to_hex_string, write_string, ClientSession, Frequency, GlobalObject,
+};
 
 //const FLUID_BUFFER_LIMIT:i64=i32::MAX as i64;//reject機能実装する時に使う
 #[derive(Clone, Debug)]
 pub struct Fluids {
 	pub(crate) data: Arc<RwLock<HashMap<FluidId, FluidStack>>>,
+	notifier: Option<Notifier>,
 }
 impl Fluids {
 	pub(crate) fn new() -> Self {
 		Self {
 			data: Arc::new(RwLock::new(HashMap::new())),
+			notifier: None,
+		}
+	}
+	pub(crate) fn with_notifier(mut self, notifier: Notifier) -> Self {
+		self.notifier = Some(notifier);
+		self
+	}
+	/// `data`を直接書き換えた後に呼ぶ
+	pub(crate) fn changed(&self, len: usize) {
+		if let Some(notifier) = &self.notifier {
+			notifier.fluids(len);
 		}
 	}
 	pub async fn take_fluid(&self, mut max_stack: FluidStack) -> Option<FluidStack> {
@@ -39,19 +57,58 @@ impl Fluids {
 			if store.count < 1 {
 				data.remove(&max_stack.id);
 			}
+			self.changed(data.len());
 			Some(max_stack)
 		}
 	}
 	pub async fn insert_fluid(&self, mut stack: FluidStack) {
+		stack.meta = StackMeta::now();
+		self.merge_fluid(stack).await;
+	}
+	/// 搬入時刻を変えずに追加する 同じ液体は新しい方の搬入時刻になる
+	pub async fn merge_fluid(&self, mut stack: FluidStack) {
 		let mut data = self.data.write().await;
 		if let Some(fluid) = data.remove(&stack.id) {
 			stack.count = stack.count.saturating_add(fluid.count);
+			stack.meta.inserted_at = stack.meta.inserted_at.max(fluid.meta.inserted_at);
+			stack.meta.id = fluid.meta.id;
 		}
 		data.insert(stack.id.clone(), stack);
+		self.changed(data.len());
+	}
+	/// `deadline`(UNIXミリ秒)より前に搬入された液体を取り出す
+	pub async fn take_expired(&self, deadline: i64) -> Vec<FluidStack> {
+		let mut data = self.data.write().await;
+		let expired = data
+			.values()
+			.filter(|fs| fs.meta.inserted_at < deadline)
+			.map(|fs| fs.id.clone())
+			.collect::<Vec<_>>();
+		let expired = expired
+			.iter()
+			.filter_map(|id| data.remove(id))
+			.collect::<Vec<_>>();
+		if !expired.is_empty() {
+			self.changed(data.len());
+		}
+		expired
+	}
+	/// `take_fluid`で搬出対象になる液体の量
+	pub async fn amount(&self, stack: &FluidStack) -> i64 {
+		let data = self.data.read().await;
+		let store = if stack.name.is_empty() {
+			data.values().next()
+		} else {
+			data.get(&stack.id)
+		};
+		store.map(|fs| fs.count).unwrap_or(0)
 	}
 	pub async fn len(&self) -> usize {
 		self.data.read().await.len()
 	}
+	pub async fn snapshot(&self) -> Vec<FluidStack> {
+		self.data.read().await.values().cloned().collect()
+	}
 }
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd)]
 pub struct FluidId(String);
@@ -73,8 +130,18 @@ pub struct FluidStack {
 	pub(crate) name: String,
 	pub(crate) count: i64,
 	pub(crate) nbt: Option<Vec<u8>>,
+	pub(crate) meta: StackMeta,
 }
 impl FluidStack {
+	pub(crate) fn new(name: String, count: i64, nbt: Option<Vec<u8>>) -> Self {
+		Self {
+			id: FluidId::new(name.clone(), nbt.as_ref()),
+			name,
+			count,
+			nbt,
+			meta: StackMeta::default(),
+		}
+	}
 	pub async fn read<R: AsyncRead + std::marker::Unpin>(
 		r: &mut R,
 	) -> Result<Self, tokio::io::Error> {
@@ -88,12 +155,7 @@ impl FluidStack {
 		} else {
 			None
 		};
-		Ok(Self {
-			id: FluidId::new(name.clone(), nbt.as_ref()),
-			name,
-			count,
-			nbt,
-		})
+		Ok(Self::new(name, count, nbt))
 	}
 	pub async fn write<W: AsyncWrite + std::marker::Unpin>(
 		&self,
@@ -106,49 +168,49 @@ impl FluidStack {
 				w.write_i16(0).await?;
 			}
 			Some(nbt) => {
-				let len = nbt
-					.len()
-					.try_into()
-					.map_err(|e| tokio::io::Error::other(e))?;
+				let len = nbt.len().try_into().map_err(tokio::io::Error::other)?;
 				w.write_i16(len).await?;
-				w.write_all(&nbt).await?;
+				w.write_all(nbt).await?;
 			}
 		}
 		Ok(())
 	}
 }
 
+impl GlobalObject {
+	/// 無ければ作る
+	pub(crate) fn fluids(&self, freq: &Frequency) -> Arc<Fluids> {
+		self.fluid_buffers.get_or_insert_with(freq, || {
+			Arc::new(Fluids::new().with_notifier(self.notifier(freq)))
+		})
+	}
+}
 impl ClientSession {
 	pub(crate) async fn fluid_recv(&mut self) -> Result<(), tokio::io::Error> {
 		let fs = FluidStack::read(&mut self.reader).await?;
-		let cache_hit = { self.go.fluid_buffers.read().await.get(self.freq()).cloned() };
-		let freq_buffer = if let Some(cache_hit) = cache_hit {
-			cache_hit
-		} else {
-			let mut fluid_buffers = self.go.fluid_buffers.write().await;
-			let freq_buffer = fluid_buffers.get(self.freq()).cloned();
-			match freq_buffer {
-				Some(v) => v,
-				None => {
-					let v = Arc::new(Fluids::new());
-					fluid_buffers.insert(self.freq().clone(), v.clone());
-					v
-				}
-			}
-		};
+		self.audit(Kind::Fluid, Direction::Insert, Some(&fs.name), fs.count)
+			.await;
+		let freq_buffer = self.go.fluids(self.freq());
 		freq_buffer.insert_fluid(fs).await;
 		Ok(())
 	}
 	pub(crate) async fn fluid_send(&mut self) -> Result<(), tokio::io::Error> {
-		let fs = FluidStack::read(&mut self.reader).await?;
-		let freq_buffer =
-			if let Some(cache_hit) = self.go.fluid_buffers.read().await.get(self.freq()) {
-				cache_hit.clone()
-			} else {
-				self.writer.write_i32(0).await?;
-				return Ok(());
-			};
+		let mut fs = FluidStack::read(&mut self.reader).await?;
+		let freq_buffer = if let Some(cache_hit) = self.go.fluid_buffers.get(self.freq()) {
+			cache_hit
+		} else {
+			self.writer.write_i32(0).await?;
+			return Ok(());
+		};
+		let resource = Resource::Fluid(fs.id.clone());
+		let available = freq_buffer.amount(&fs).await;
+		fs.count = fs
+			.count
+			.min(self.fair_share(resource.clone(), available).await);
 		if let Some(fs) = freq_buffer.take_fluid(fs).await {
+			self.fair_took(&resource, fs.count).await;
+			self.audit(Kind::Fluid, Direction::Take, Some(&fs.name), fs.count)
+				.await;
 			let mut write_buffer = Vec::new();
 			fs.write(&mut write_buffer).await?;
 			self.writer.write_i32(write_buffer.len() as i32).await?;
@@ -158,6 +220,21 @@ impl ClientSession {
 		}
 		Ok(())
 	}
+	/// 搬入出せずに液体の種類ごとの量を返す
+	pub(crate) async fn fluid_query(&mut self) -> Result<(), tokio::io::Error> {
+		let freq_buffer = self.go.fluid_buffers.get(self.freq());
+		let fluids = match freq_buffer {
+			Some(freq_buffer) => freq_buffer.snapshot().await,
+			None => Vec::new(),
+		};
+		let mut write_buffer = Vec::new();
+		write_buffer.write_i32(fluids.len() as i32).await?;
+		for fs in &fluids {
+			fs.write(&mut write_buffer).await?;
+		}
+		self.writer.write_all(&write_buffer).await?;
+		Ok(())
+	}
 }
 
 #[cfg(test)]
@@ -181,6 +258,7 @@ mod tests {
 				name,
 				count: i32::MAX as i64 + 100,
 				nbt,
+				meta: Default::default(),
 			}
 		}
 		fn any() -> Self {
@@ -191,6 +269,7 @@ mod tests {
 				name,
 				count: i32::MAX as i64 + 100,
 				nbt,
+				meta: Default::default(),
 			}
 		}
 	}
@@ -0,0 +1,97 @@
+use std::{
+	collections::HashMap,
+	hash::{BuildHasher, RandomState},
+	sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::Frequency;
+
+const SHARD_COUNT: usize = 16;
+
+/// 周波数をキーにしたシャード分割マップ
+///
+/// ロックは値の取り出し中だけ保持するので、異なる周波数同士や
+/// スナップショット中の読み取りが互いを待たせない
+pub struct FrequencyMap<V> {
+	hasher: RandomState,
+	shards: Vec<RwLock<HashMap<Frequency, V>>>,
+}
+impl<V: Clone> FrequencyMap<V> {
+	pub fn new() -> Self {
+		Self {
+			hasher: RandomState::new(),
+			shards: (0..SHARD_COUNT)
+				.map(|_| RwLock::new(HashMap::new()))
+				.collect(),
+		}
+	}
+	fn shard(&self, freq: &Frequency) -> &RwLock<HashMap<Frequency, V>> {
+		let hash = self.hasher.hash_one(freq) as usize;
+		&self.shards[hash % self.shards.len()]
+	}
+	fn read(shard: &RwLock<HashMap<Frequency, V>>) -> RwLockReadGuard<'_, HashMap<Frequency, V>> {
+		shard.read().unwrap_or_else(|e| e.into_inner())
+	}
+	fn write(shard: &RwLock<HashMap<Frequency, V>>) -> RwLockWriteGuard<'_, HashMap<Frequency, V>> {
+		shard.write().unwrap_or_else(|e| e.into_inner())
+	}
+	pub fn get(&self, freq: &Frequency) -> Option<V> {
+		Self::read(self.shard(freq)).get(freq).cloned()
+	}
+	/// 無ければ`f`で作って登録する
+	pub fn get_or_insert_with(&self, freq: &Frequency, f: impl FnOnce() -> V) -> V {
+		if let Some(v) = self.get(freq) {
+			return v;
+		}
+		let mut shard = Self::write(self.shard(freq));
+		shard.entry(freq.clone()).or_insert_with(f).clone()
+	}
+	/// `f`が真なら削除する 判定中は他から取得できない
+	pub fn remove_if(&self, freq: &Frequency, f: impl FnOnce(&V) -> bool) -> Option<V> {
+		let mut shard = Self::write(self.shard(freq));
+		if shard.get(freq).is_some_and(f) {
+			shard.remove(freq)
+		} else {
+			None
+		}
+	}
+	/// 現時点の全要素の複製 シャードごとに順にロックする
+	pub fn snapshot(&self) -> Vec<(Frequency, V)> {
+		let mut v = Vec::new();
+		for shard in &self.shards {
+			let shard = Self::read(shard);
+			v.extend(shard.iter().map(|(k, v)| (k.clone(), v.clone())));
+		}
+		v
+	}
+}
+impl<V: Clone> Default for FrequencyMap<V> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::FrequencyMap;
+	use crate::Frequency;
+
+	#[test]
+	fn frequency_map() {
+		let map = FrequencyMap::new();
+		let freq = |i: i32| Frequency(format!("F{}", i));
+		for i in 0..100 {
+			assert_eq!(map.get_or_insert_with(&freq(i), || i), i);
+		}
+		assert_eq!(map.get_or_insert_with(&freq(5), || -1), 5);
+		assert_eq!(map.get(&freq(5)), Some(5));
+		assert_eq!(map.get(&freq(100)), None);
+		assert_eq!(map.remove_if(&freq(6), |v| *v == 7), None);
+		assert_eq!(map.remove_if(&freq(6), |v| *v == 6), Some(6));
+		assert_eq!(map.get(&freq(6)), None);
+		let mut snapshot = map.snapshot();
+		snapshot.sort_by_key(|(_, v)| *v);
+		assert_eq!(snapshot.len(), 99);
+		assert_eq!(snapshot.last(), Some(&(freq(99), 99)));
+	}
+}
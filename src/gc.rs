@@ -0,0 +1,252 @@
+use std::{
+	collections::HashMap,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use crate::{events::Event, fluid::Fluids, item::Items, Frequency, GlobalObject};
+
+const GC_INTERVAL_SECS: u64 = 10;
+const EMPTY_FREQUENCY_GRACE_SECS: u64 = 300;
+
+/// 空になってからの時刻
+#[derive(Default)]
+pub(crate) struct EmptySince {
+	items: HashMap<Frequency, Instant>,
+	fluids: HashMap<Frequency, Instant>,
+}
+/// 期限切れのスタック、空のまま猶予を過ぎた周波数、参照されなくなったNBTを定期的に削除する
+pub(crate) async fn gc_loop(go: Arc<GlobalObject>) {
+	let mut interval = tokio::time::interval(Duration::from_secs(GC_INTERVAL_SECS));
+	let mut empty_since = EmptySince::default();
+	loop {
+		interval.tick().await;
+		go.expire_stacks().await;
+		let grace = go
+			.config
+			.read()
+			.await
+			.empty_frequency_grace_secs
+			.unwrap_or(EMPTY_FREQUENCY_GRACE_SECS);
+		go.collect_empty(&mut empty_since, Duration::from_secs(grace))
+			.await;
+		go.fair_shares.purge();
+		go.nbt_store.purge();
+		go.nbt_display.purge(&go.nbt_store);
+	}
+}
+fn expired(
+	empty_since: &mut HashMap<Frequency, Instant>,
+	freq: &Frequency,
+	is_empty: bool,
+	grace: Duration,
+) -> bool {
+	if !is_empty {
+		empty_since.remove(freq);
+		return false;
+	}
+	let since = empty_since.entry(freq.clone()).or_insert_with(Instant::now);
+	since.elapsed() >= grace
+}
+impl GlobalObject {
+	/// 期限切れのスタックを設定された周波数に移すか削除する
+	pub(crate) async fn expire_stacks(&self) {
+		let config = self.config.read().await.clone();
+		let now = chrono::Utc::now().timestamp_millis();
+		for (freq, freq_config) in config.frequencies {
+			let Some(ttl_secs) = freq_config.ttl_secs else {
+				continue;
+			};
+			let freq = Frequency(freq);
+			let deadline = now.saturating_sub((ttl_secs as i64).saturating_mul(1000));
+			let expire_to = freq_config.expire_to.map(Frequency);
+			if let Some(items) = self.item_buffers.get(&freq) {
+				let mut expired = items.take_expired(deadline).await;
+				if let Some(expire_to) = &expire_to {
+					if !expired.is_empty() {
+						self.items(expire_to).insert_items(&mut expired).await;
+						//移動先が一杯なら次回まで元に残す
+						items.restore_items(&mut expired).await;
+					}
+				}
+				if !expired.is_empty() {
+					let count = expired.len();
+					tracing::info!(count, frequency = freq.0, "drop expired stacks");
+				}
+			}
+			if let Some(fluids) = self.fluid_buffers.get(&freq) {
+				let expired = fluids.take_expired(deadline).await;
+				if let Some(expire_to) = &expire_to {
+					for fs in expired {
+						self.fluids(expire_to).insert_fluid(fs).await;
+					}
+				} else if !expired.is_empty() {
+					let count = expired.len();
+					tracing::info!(count, frequency = freq.0, "drop expired fluids");
+				}
+			}
+		}
+	}
+	/// 削除は他に参照が無く空の場合のみ行うので、搬入中の周波数とは競合しない
+	///
+	/// エネルギーは損失の統計とレート制限の集計期間を持つので、空になっても削除しない
+	pub(crate) async fn collect_empty(&self, empty_since: &mut EmptySince, grace: Duration) {
+		for (freq, items) in self.item_buffers.snapshot() {
+			let is_empty = items.len().await == 0;
+			drop(items);
+			if expired(&mut empty_since.items, &freq, is_empty, grace) {
+				let removed = self.item_buffers.remove_if(&freq, |items: &Arc<Items>| {
+					Arc::strong_count(items) == 1
+						&& items.data.try_read().is_ok_and(|data| data.is_empty())
+				});
+				if removed.is_some() {
+					empty_since.items.remove(&freq);
+					self.send_event(Event::FrequencyRemoved {
+						kind: "item",
+						id: freq.0.clone(),
+					});
+				}
+			}
+		}
+		for (freq, fluids) in self.fluid_buffers.snapshot() {
+			let is_empty = fluids.len().await == 0;
+			drop(fluids);
+			if expired(&mut empty_since.fluids, &freq, is_empty, grace) {
+				let removed = self.fluid_buffers.remove_if(&freq, |fluids: &Arc<Fluids>| {
+					Arc::strong_count(fluids) == 1
+						&& fluids.data.try_read().is_ok_and(|data| data.is_empty())
+				});
+				if removed.is_some() {
+					empty_since.fluids.remove(&freq);
+					self.send_event(Event::FrequencyRemoved {
+						kind: "fluid",
+						id: freq.0.clone(),
+					});
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::EmptySince;
+	use crate::{
+		config::Config,
+		item::{ItemOrder, ItemStack},
+		Frequency, GlobalObject,
+	};
+
+	#[test]
+	fn collect_empty() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let red = Frequency("RED, RED, RED".into());
+				let blue = Frequency("WHITE, BLUE, WHITE".into());
+				go.items(&red).take_items(100, &ItemOrder::Fifo).await;
+				go.items(&blue).take_items(100, &ItemOrder::Fifo).await;
+				go.energy(&Frequency("BLACK".into()));
+				let mut empty_since = EmptySince::default();
+				//猶予中は残す
+				go.collect_empty(&mut empty_since, Duration::from_secs(60))
+					.await;
+				assert_eq!(go.item_buffers.snapshot().len(), 2);
+				//使用中の周波数は残す
+				let in_use = go.items(&blue);
+				go.collect_empty(&mut empty_since, Duration::ZERO).await;
+				assert!(go.item_buffers.get(&red).is_none());
+				assert!(go.item_buffers.get(&blue).is_some());
+				assert!(go.fluid_buffers.get(&red).is_some());
+				//レート制限と統計を失わないようエネルギーは残す
+				assert!(go.energy_buffers.get(&Frequency("BLACK".into())).is_some());
+				drop(in_use);
+				go.collect_empty(&mut empty_since, Duration::ZERO).await;
+				assert!(go.item_buffers.get(&blue).is_none());
+			});
+	}
+	#[test]
+	fn expire_stacks() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let json = r#"{
+					"frequencies": {
+						"RED, RED, RED": { "ttl_secs": 0, "expire_to": "LOST" },
+						"WHITE, BLUE, WHITE": { "ttl_secs": 0 },
+						"KEEP": { "ttl_secs": 3600 }
+					}
+				}"#;
+				let config: Config = serde_json::from_str(json).unwrap();
+				let go = GlobalObject::dummy().await;
+				*go.config.write().await = config;
+				go.items(&Frequency("KEEP".into()))
+					.insert_items(&mut vec![crate::item::ItemStack::dummy()])
+					.await;
+				//搬入時刻と同じミリ秒内だと期限切れにならない
+				tokio::time::sleep(Duration::from_millis(2)).await;
+				go.expire_stacks().await;
+				let len = |freq: &str| {
+					let items = go.item_buffers.get(&Frequency(freq.into()));
+					async move {
+						match items {
+							Some(items) => items.len().await,
+							None => 0,
+						}
+					}
+				};
+				assert_eq!(len("RED, RED, RED").await, 0);
+				assert_eq!(len("WHITE, BLUE, WHITE").await, 0);
+				assert_eq!(len("LOST").await, 1);
+				assert_eq!(len("KEEP").await, 1);
+				let lost = go.fluid_buffers.get(&Frequency("LOST".into())).unwrap();
+				assert_eq!(lost.len().await, 1);
+				assert_eq!(
+					go.fluid_buffers
+						.get(&Frequency("RED, RED, RED".into()))
+						.unwrap()
+						.len()
+						.await,
+					0
+				);
+			});
+	}
+	#[test]
+	fn expire_to_full() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let json = r#"{
+					"frequencies": {
+						"RED, RED, RED": { "ttl_secs": 0, "expire_to": "FULL" }
+					}
+				}"#;
+				let config: Config = serde_json::from_str(json).unwrap();
+				let go = GlobalObject::dummy().await;
+				*go.config.write().await = config;
+				let full = go.items(&Frequency("FULL".into()));
+				full.insert_items(&mut vec![ItemStack::dummy(); 100]).await;
+				let red = go.items(&Frequency("RED, RED, RED".into()));
+				let id = red.data.read().await[0].meta.id;
+				tokio::time::sleep(Duration::from_millis(2)).await;
+				go.expire_stacks().await;
+				//移動先が一杯なら捨てずに元に残す
+				assert_eq!(full.len().await, 100);
+				assert_eq!(red.len().await, 1);
+				assert_eq!(red.data.read().await[0].meta.id, id);
+				full.take_items(1, &ItemOrder::Fifo).await;
+				go.expire_stacks().await;
+				assert_eq!(full.len().await, 100);
+				assert_eq!(red.len().await, 0);
+			});
+	}
+}
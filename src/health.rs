@@ -0,0 +1,118 @@
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Mutex,
+};
+
+use serde::Serialize;
+
+use crate::GlobalObject;
+
+/// `/readyz`で返す起動・保存の状態
+#[derive(Debug, Default)]
+pub struct Health {
+	listener_bound: AtomicBool,
+	save_load: Mutex<SaveLoad>,
+	last_save: Mutex<Option<SaveResult>>,
+}
+/// 起動時の読み込みの状態
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SaveLoad {
+	#[default]
+	Pending,
+	Loaded,
+	/// 保存ファイルが無く空のまま始めた
+	NotFound,
+	/// `autoload`が無効で読み込んでいない
+	NotConfigured,
+	/// 保存ファイルが読み込めなかった 上書きしないよう強制しない限り保存しない
+	Failed,
+}
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SaveResult {
+	/// UNIXミリ秒
+	pub time: i64,
+	pub error: Option<String>,
+}
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+	pub ready: bool,
+	/// TCPの待ち受けを開始した
+	pub listener_bound: bool,
+	/// 起動時の読み込みが終わった `autoload`が無効なら読み込まずに真になる
+	pub save_loaded: bool,
+	/// 読み込んだか、読み込まなかった理由
+	pub save_load: SaveLoad,
+	/// 最後の保存 まだ保存していなければnull
+	pub last_save: Option<SaveResult>,
+	pub shutting_down: bool,
+}
+impl Health {
+	pub fn listener_bound(&self) {
+		self.listener_bound.store(true, Ordering::Relaxed);
+	}
+	pub fn save_loaded(&self, state: SaveLoad) {
+		*self.save_load.lock().unwrap_or_else(|e| e.into_inner()) = state;
+	}
+	pub fn saved(&self, result: &Result<(), tokio::io::Error>) {
+		let result = SaveResult {
+			time: chrono::Utc::now().timestamp_millis(),
+			error: result.as_ref().err().map(|e| e.to_string()),
+		};
+		*self.last_save.lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+	}
+}
+impl GlobalObject {
+	pub(crate) fn readiness(&self) -> Readiness {
+		let health = &self.health;
+		let listener_bound = health.listener_bound.load(Ordering::Relaxed);
+		let save_load = *health.save_load.lock().unwrap_or_else(|e| e.into_inner());
+		let save_loaded = !matches!(save_load, SaveLoad::Pending | SaveLoad::Failed);
+		let last_save = health
+			.last_save
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.clone();
+		let shutting_down = *self.shutdown.borrow();
+		let save_ok = last_save.as_ref().is_none_or(|s| s.error.is_none());
+		Readiness {
+			ready: listener_bound && save_loaded && save_ok && !shutting_down,
+			listener_bound,
+			save_loaded,
+			save_load,
+			last_save,
+			shutting_down,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::SaveLoad;
+	use crate::GlobalObject;
+
+	#[test]
+	fn readiness() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				assert!(!go.readiness().ready);
+				go.health.listener_bound();
+				go.health.save_loaded(SaveLoad::NotConfigured);
+				let readiness = go.readiness();
+				assert!(readiness.ready);
+				assert_eq!(readiness.save_load, SaveLoad::NotConfigured);
+				go.health.saved(&Err(tokio::io::Error::other("disk full")));
+				let readiness = go.readiness();
+				assert!(!readiness.ready);
+				assert_eq!(readiness.last_save.unwrap().error.unwrap(), "disk full");
+				go.health.saved(&Ok(()));
+				assert!(go.readiness().ready);
+				go.shutdown.send_replace(true);
+				assert!(!go.readiness().ready);
+			});
+	}
+}
@@ -0,0 +1,206 @@
+use std::{
+	collections::{BTreeMap, VecDeque},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::GlobalObject;
+
+const HISTORY_INTERVAL_SECS: u64 = 60;
+/// 既定の間隔で1日分
+const HISTORY_LEN: usize = 1440;
+
+/// 周波数ごとの残量
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+	pub item_stacks: usize,
+	pub item_count: i64,
+	pub fluid_amount: i64,
+	pub energy: i64,
+}
+impl std::ops::AddAssign for Level {
+	fn add_assign(&mut self, rhs: Self) {
+		//液体とエネルギーはi64の上限近くまで溜まり得るので溢れたら上限で止める
+		self.item_stacks = self.item_stacks.saturating_add(rhs.item_stacks);
+		self.item_count = self.item_count.saturating_add(rhs.item_count);
+		self.fluid_amount = self.fluid_amount.saturating_add(rhs.fluid_amount);
+		self.energy = self.energy.saturating_add(rhs.energy);
+	}
+}
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+	/// UNIXミリ秒
+	pub time: i64,
+	pub frequencies: BTreeMap<String, Level>,
+}
+/// 一定間隔で記録した残量 古いものから捨てる
+#[derive(Debug, Default)]
+pub struct History {
+	samples: Mutex<VecDeque<Sample>>,
+}
+impl History {
+	fn samples(&self) -> std::sync::MutexGuard<'_, VecDeque<Sample>> {
+		self.samples.lock().unwrap_or_else(|e| e.into_inner())
+	}
+	pub fn push(&self, sample: Sample, max_len: usize) {
+		let mut samples = self.samples();
+		samples.push_back(sample);
+		while samples.len() > max_len {
+			samples.pop_front();
+		}
+	}
+	/// `since`(UNIXミリ秒)以降の記録 周波数を指定しなければ合計
+	pub fn query(&self, freq: Option<&str>, since: i64) -> Vec<(i64, Level)> {
+		let samples = self.samples();
+		let samples = samples.iter().filter(|sample| sample.time >= since);
+		samples
+			.map(|sample| {
+				let level = match freq {
+					Some(freq) => sample.frequencies.get(freq).copied().unwrap_or_default(),
+					None => {
+						let mut total = Level::default();
+						for level in sample.frequencies.values() {
+							total += *level;
+						}
+						total
+					}
+				};
+				(sample.time, level)
+			})
+			.collect()
+	}
+	fn to_vec(&self) -> Vec<Sample> {
+		self.samples().iter().cloned().collect()
+	}
+}
+impl GlobalObject {
+	pub(crate) async fn sample_levels(&self) -> Sample {
+		let mut frequencies = BTreeMap::<String, Level>::new();
+		for (freq, items) in self.item_buffers.snapshot() {
+			let data = items.data.read().await;
+			let level = frequencies.entry(freq.0).or_default();
+			level.item_stacks = data.len();
+			level.item_count = data
+				.iter()
+				.fold(0i64, |sum, is| sum.saturating_add(is.count as i64));
+		}
+		for (freq, fluids) in self.fluid_buffers.snapshot() {
+			let fluids = fluids.snapshot().await;
+			let amount = fluids
+				.iter()
+				.fold(0i64, |sum, fs| sum.saturating_add(fs.count));
+			frequencies.entry(freq.0).or_default().fluid_amount = amount;
+		}
+		for (freq, energy) in self.energy_buffers.snapshot() {
+			frequencies.entry(freq.0).or_default().energy = energy.value();
+		}
+		Sample {
+			time: chrono::Utc::now().timestamp_millis(),
+			frequencies,
+		}
+	}
+}
+/// 設定された間隔で残量を記録し、ファイルが指定されていれば保存する
+pub(crate) async fn history_loop(go: Arc<GlobalObject>) {
+	if let Some(path) = go.config.read().await.history_file.clone() {
+		match load_file(&path).await {
+			Ok(samples) => {
+				let max_len = go.config.read().await.history_len.unwrap_or(HISTORY_LEN);
+				for sample in samples {
+					go.history.push(sample, max_len);
+				}
+			}
+			Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => {}
+			Err(e) => tracing::error!(path, error = %e, "history load error"),
+		}
+	}
+	loop {
+		let config = go.config.read().await.clone();
+		let interval = config
+			.history_interval_secs
+			.unwrap_or(HISTORY_INTERVAL_SECS);
+		tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+		let sample = go.sample_levels().await;
+		go.history
+			.push(sample, config.history_len.unwrap_or(HISTORY_LEN));
+		if let Some(path) = &config.history_file {
+			if let Err(e) = save_file(path, &go.history.to_vec()).await {
+				tracing::error!(path, error = %e, "history save error");
+			}
+		}
+	}
+}
+async fn load_file(path: &str) -> Result<Vec<Sample>, tokio::io::Error> {
+	let data = tokio::fs::read(path).await?;
+	serde_json::from_slice(&data).map_err(tokio::io::Error::other)
+}
+/// 書き込み途中で落ちても前の記録が残るように別名で書いてから置き換える
+async fn save_file(path: &str, samples: &[Sample]) -> Result<(), tokio::io::Error> {
+	let data = serde_json::to_vec(samples).map_err(tokio::io::Error::other)?;
+	let tmp = format!("{}.tmp", path);
+	tokio::fs::write(&tmp, data).await?;
+	tokio::fs::rename(tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{History, Level};
+	use crate::{fluid::FluidStack, Frequency, GlobalObject};
+
+	#[test]
+	fn history() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let history = History::default();
+				for i in 0..5 {
+					let mut sample = go.sample_levels().await;
+					sample.time = i;
+					history.push(sample, 3);
+				}
+				let red = history.query(Some("RED, RED, RED"), 0);
+				assert_eq!(red.iter().map(|(t, _)| *t).collect::<Vec<_>>(), [2, 3, 4]);
+				assert_eq!(
+					red[0].1,
+					Level {
+						item_stacks: 1,
+						item_count: 64,
+						fluid_amount: i32::MAX as i64 + 100,
+						energy: 0,
+					}
+				);
+				let total = history.query(None, 4);
+				assert_eq!(total.len(), 1);
+				assert_eq!(total[0].1.item_stacks, 2);
+				assert_eq!(total[0].1.energy, u32::MAX as i64 + 500);
+				assert_eq!(history.query(Some("NONE"), 0)[0].1, Level::default());
+			});
+	}
+	#[test]
+	fn total_saturates() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				for freq in ["A", "B"] {
+					let freq = Frequency(freq.into());
+					go.energy(&freq).merge(i64::MAX);
+					let lava = FluidStack::new("lava".into(), i64::MAX, None);
+					go.fluids(&freq).insert_fluid(lava).await;
+				}
+				let history = History::default();
+				history.push(go.sample_levels().await, 1);
+				//合計が溢れたら上限で止める
+				let total = history.query(None, 0);
+				assert_eq!(total[0].1.energy, i64::MAX);
+				assert_eq!(total[0].1.fluid_amount, i64::MAX);
+			});
+	}
+}
@@ -2,33 +2,66 @@ use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
 	extract::{Query, State},
-	http::StatusCode,
+	http::{header, StatusCode},
 	response::{IntoResponse, Response},
-	routing::get,
+	routing::{get, post},
 	Router,
 };
 use serde::{Deserialize, Serialize};
 use tower_http::services::ServeDir;
 
-use crate::{to_hex_string, GlobalObject};
+use crate::{
+	energy::energy_capacity,
+	item::NBT,
+	nbt::{Enchantment, Tag},
+	to_hex_string, GlobalObject,
+};
+
+mod admin;
+mod events;
+mod write;
 
 pub(crate) async fn server(go: Arc<GlobalObject>) {
 	let http_addr: SocketAddr = "0.0.0.0:3031".parse().unwrap();
 	let app = Router::new();
 	let app = app.route("/api/list/item_frequency.json", get(item_frequency));
 	let app = app.route("/api/list/items.json", get(items));
+	let app = app.route("/api/item.json", get(item));
+	let app = app.route("/api/item.nbt", get(item_nbt));
 	let app = app.route("/api/list/fluid_frequency.json", get(fluid_frequency));
 	let app = app.route("/api/list/fluids.json", get(fluids));
 	let app = app.route("/api/list/energy_frequency.json", get(energy_frequency));
 	let app = app.route("/api/list/clients.json", get(clients));
+	let app = app.route("/api/events", get(events::events));
+	let app = app.route("/metrics", get(metrics));
+	let app = app.route("/healthz", get(healthz));
+	let app = app.route("/readyz", get(readyz));
+	let app = app.route("/api/history.json", get(history));
+	let app = app.route("/api/audit.json", get(admin::audit));
+	let app = app.route("/api/search.json", get(search));
+	let app = app.route("/api/summary.json", get(summary));
+	let app = app.route("/api/write/items/insert", post(write::item_insert));
+	let app = app.route("/api/write/items/take", post(write::item_take));
+	let app = app.route("/api/write/fluids/insert", post(write::fluid_insert));
+	let app = app.route("/api/write/fluids/take", post(write::fluid_take));
+	let app = app.route("/api/write/energy/insert", post(write::energy_insert));
+	let app = app.route("/api/write/energy/take", post(write::energy_take));
+	let app = app.route("/api/admin/save", post(admin::save));
+	let app = app.route("/api/admin/load", post(admin::load));
+	let app = app.route("/api/admin/reload", post(admin::reload));
+	let app = app.route("/api/admin/stop", post(admin::stop));
+	let app = app.route("/api/admin/clear", post(admin::clear));
+	let app = app.route("/api/admin/move", post(admin::move_contents));
+	let app = app.route("/api/admin/log", post(admin::log_level));
 	let app = app.fallback_service(ServeDir::new("html"));
-	let app = app.with_state(go);
+	let app = app.with_state(go.clone());
 	let listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
+	tracing::info!(%http_addr, "http listening");
 	axum::serve(
 		listener,
 		app.into_make_service_with_connect_info::<SocketAddr>(),
 	)
-	.with_graceful_shutdown(shutdown_signal())
+	.with_graceful_shutdown(shutdown_signal(go))
 	.await
 	.unwrap();
 }
@@ -40,12 +73,7 @@ async fn fluids(
 	State(go): State<Arc<GlobalObject>>,
 	Query(params): Query<ParmFreqList>,
 ) -> Response {
-	let fluid_buffers = go
-		.fluid_buffers
-		.read()
-		.await
-		.get(&crate::Frequency(params.frequency))
-		.cloned();
+	let fluid_buffers = go.fluid_buffers.get(&crate::Frequency(params.frequency));
 	let fluid_buffers = match fluid_buffers {
 		Some(v) => v,
 		None => {
@@ -60,8 +88,8 @@ async fn fluids(
 	}
 	let fluids = {
 		let fluids = fluid_buffers.data.read().await;
-		let jobs = fluids.iter().map(|(_id, fluid)| async {
-			let nbt = fluid.nbt.as_ref().map(|b| to_hex_string(&b));
+		let jobs = fluids.values().map(|fluid| async {
+			let nbt = fluid.nbt.as_ref().map(|b| to_hex_string(b));
 			ItemStack {
 				name: fluid.name.clone(),
 				count: fluid.count,
@@ -78,36 +106,148 @@ async fn fluids(
 		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
 	}
 }
-async fn items(
-	State(go): State<Arc<GlobalObject>>,
-	Query(params): Query<ParmFreqList>,
-) -> Response {
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ItemSort {
+	/// IDのパス部分、次にMod ID
+	Name,
+	Modid,
+	Count,
+}
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+	Asc,
+	Desc,
+}
+/// 未指定の条件は絞り込まない 並び順の既定はcountが降順、それ以外は昇順
+#[derive(Debug, Deserialize)]
+struct ParmItems {
+	frequency: String,
+	/// アイテムIDの部分一致 大文字小文字は区別しない
+	q: Option<String>,
+	/// Mod IDの完全一致
+	modid: Option<String>,
+	sort: Option<ItemSort>,
+	order: Option<SortOrder>,
+	#[serde(default)]
+	offset: usize,
+	limit: Option<usize>,
+	/// アイテムIDごとに数を合計する
+	#[serde(default)]
+	aggregate: bool,
+}
+/// `modid:name`を分ける Mod IDが無ければminecraft
+fn split_id(id: &str) -> (&str, &str) {
+	id.split_once(':').unwrap_or(("minecraft", id))
+}
+impl ParmItems {
+	fn matches(&self, id: &str) -> bool {
+		let q = self.q.as_ref().map(|q| q.to_lowercase());
+		q.is_none_or(|q| id.to_lowercase().contains(&q))
+			&& self
+				.modid
+				.as_ref()
+				.is_none_or(|modid| split_id(id).0 == modid)
+	}
+	fn sort<T>(&self, v: &mut [T], key: impl Fn(&T) -> (&str, i64)) {
+		let Some(sort) = self.sort else {
+			return;
+		};
+		let default = match sort {
+			ItemSort::Count => SortOrder::Desc,
+			_ => SortOrder::Asc,
+		};
+		v.sort_by(|a, b| {
+			let (a, b) = (key(a), key(b));
+			let (a_mod, a_name) = split_id(a.0);
+			let (b_mod, b_name) = split_id(b.0);
+			let ordering = match sort {
+				ItemSort::Name => (a_name, a_mod).cmp(&(b_name, b_mod)),
+				ItemSort::Modid => (a_mod, a_name).cmp(&(b_mod, b_name)),
+				ItemSort::Count => a.1.cmp(&b.1),
+			};
+			match self.order.unwrap_or(default) {
+				SortOrder::Asc => ordering,
+				SortOrder::Desc => ordering.reverse(),
+			}
+		});
+	}
+	/// 並べ替え済みの一覧から切り出す
+	fn page<T>(&self, v: Vec<T>) -> Vec<T> {
+		let limit = self.limit.unwrap_or(usize::MAX);
+		v.into_iter().skip(self.offset).take(limit).collect()
+	}
+}
+/// 絞り込み後の件数は`X-Total-Count`で返す
+fn paged_response<T: Serialize>(total: usize, value: &T) -> Response {
+	let mut res = json_response(value);
+	res.headers_mut()
+		.insert("x-total-count", header::HeaderValue::from(total));
+	res
+}
+async fn items(State(go): State<Arc<GlobalObject>>, Query(params): Query<ParmItems>) -> Response {
 	let item_buffers = go
 		.item_buffers
-		.read()
-		.await
-		.get(&crate::Frequency(params.frequency))
-		.cloned();
+		.get(&crate::Frequency(params.frequency.clone()));
 	let item_buffers = match item_buffers {
 		Some(v) => v,
 		None => {
-			return (StatusCode::OK, "[]".to_owned()).into_response();
+			return paged_response(0, &[(); 0]);
 		}
 	};
+	let mut items = item_buffers.data.read().await.clone();
+	items.retain(|item| params.matches(&item.id));
+	if params.aggregate {
+		#[derive(Serialize, Debug)]
+		struct ItemTotal {
+			name: String,
+			count: i64,
+			stacks: usize,
+		}
+		let mut totals = std::collections::BTreeMap::<&str, (i64, usize)>::new();
+		for item in &items {
+			let total = totals.entry(&item.id).or_default();
+			total.0 += item.count as i64;
+			total.1 += 1;
+		}
+		let mut totals = totals
+			.into_iter()
+			.map(|(name, (count, stacks))| ItemTotal {
+				name: name.to_owned(),
+				count,
+				stacks,
+			})
+			.collect::<Vec<_>>();
+		params.sort(&mut totals, |t| (&t.name, t.count));
+		let total = totals.len();
+		return paged_response(total, &params.page(totals));
+	}
+	params.sort(&mut items, |item| (&item.id, item.count as i64));
+	let total = items.len();
+	let items = params.page(items);
 	#[derive(Serialize, Debug)]
 	struct ItemStack {
+		id: u64,
 		name: String,
 		count: i32,
+		damage: i32,
 		nbt: Option<String>,
+		display_name: Option<String>,
+		enchantments: Vec<Enchantment>,
 	}
 	let items = {
-		let items = item_buffers.data.read().await;
 		let jobs = items.iter().map(|item| async {
 			let nbt = item.nbt.as_ref().map(|b| b.hint());
+			let display = go.item_display(item).await;
 			ItemStack {
+				id: item.meta.id,
 				name: item.id.clone(),
 				count: item.count,
+				damage: item.damage,
 				nbt,
+				display_name: display.display_name,
+				enchantments: display.enchantments,
 			}
 		});
 		futures::future::join_all(jobs)
@@ -115,13 +255,124 @@ async fn items(
 			.into_iter()
 			.collect::<Vec<_>>()
 	};
-	match serde_json::to_string(&items) {
+	paged_response(total, &items)
+}
+async fn decode_nbt(item: &crate::item::ItemStack) -> Result<Option<Tag>, std::io::Error> {
+	match item.nbt.as_ref() {
+		Some(nbt) => nbt.decode().await,
+		None => Ok(None),
+	}
+}
+#[derive(Debug, Deserialize)]
+struct ParmItem {
+	frequency: String,
+	id: u64,
+}
+async fn find_item(go: &GlobalObject, params: &ParmItem) -> Option<crate::item::ItemStack> {
+	let item_buffers = go
+		.item_buffers
+		.get(&crate::Frequency(params.frequency.clone()))?;
+	item_buffers.find(params.id).await
+}
+/// 1スタックの詳細 NBTはJSONに変換して返す
+async fn item(State(go): State<Arc<GlobalObject>>, Query(params): Query<ParmItem>) -> Response {
+	let item = match find_item(&go, &params).await {
+		Some(v) => v,
+		None => {
+			return (StatusCode::NOT_FOUND, "item not found".to_owned()).into_response();
+		}
+	};
+	#[derive(Serialize, Debug)]
+	struct ItemDetail {
+		name: String,
+		count: i32,
+		damage: i32,
+		nbt_hint: Option<String>,
+		display_name: Option<String>,
+		enchantments: Vec<Enchantment>,
+		nbt: Option<serde_json::Value>,
+		error: Option<String>,
+	}
+	let (tag, error) = match decode_nbt(&item).await {
+		Ok(tag) => (tag, None),
+		Err(e) => (None, Some(e.to_string())),
+	};
+	let detail = ItemDetail {
+		name: item.id.clone(),
+		count: item.count,
+		damage: item.damage,
+		nbt_hint: item.nbt.as_ref().map(|b| b.hint()),
+		display_name: tag.as_ref().and_then(Tag::display_name),
+		enchantments: tag.as_ref().map(Tag::enchantments).unwrap_or_default(),
+		nbt: tag.as_ref().map(Tag::to_json),
+		error,
+	};
+	match serde_json::to_string(&detail) {
 		Ok(json) => (StatusCode::OK, json).into_response(),
 		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
 	}
 }
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NbtFormat {
+	/// 保存している形式のまま Rawはそのまま、GzipNBTはgzipのまま
+	#[default]
+	Binary,
+	/// gzipを展開したバイナリ
+	Raw,
+	Snbt,
+}
+#[derive(Debug, Deserialize)]
+struct ParmItemNbt {
+	#[serde(flatten)]
+	item: ParmItem,
+	#[serde(default)]
+	format: NbtFormat,
+}
+/// 1スタックのNBTをダウンロードする
+async fn item_nbt(
+	State(go): State<Arc<GlobalObject>>,
+	Query(params): Query<ParmItemNbt>,
+) -> Response {
+	let item = find_item(&go, &params.item).await;
+	let nbt = match item.and_then(|item| item.nbt) {
+		Some(NBT::Extra(None)) | None => {
+			return (StatusCode::NOT_FOUND, "nbt not found".to_owned()).into_response();
+		}
+		Some(nbt) => nbt,
+	};
+	let raw = match (&nbt, &params.format) {
+		(NBT::Extra(Some(gz)), NbtFormat::Binary) => {
+			let file_name = format!("attachment; filename=\"{}.nbt.gz\"", params.item.id);
+			return binary(file_name, gz.as_gzip().to_vec());
+		}
+		(NBT::Raw(raw), _) => raw.clone(),
+		(NBT::Extra(Some(gz)), _) => match gz.to_raw().await {
+			Ok(raw) => raw,
+			Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+		},
+		(NBT::Extra(None), _) => unreachable!(),
+	};
+	match params.format {
+		NbtFormat::Snbt => match Tag::decode(&raw) {
+			Ok(tag) => (StatusCode::OK, tag.to_string()).into_response(),
+			Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+		},
+		_ => {
+			let file_name = format!("attachment; filename=\"{}.nbt\"", params.item.id);
+			binary(file_name, raw)
+		}
+	}
+}
+fn binary(content_disposition: String, data: Vec<u8>) -> Response {
+	let headers = [
+		(header::CONTENT_TYPE, "application/octet-stream".to_owned()),
+		(header::CONTENT_DISPOSITION, content_disposition),
+	];
+	(StatusCode::OK, headers, data).into_response()
+}
 async fn item_frequency(State(go): State<Arc<GlobalObject>>) -> Response {
-	let item_buffers = go.item_buffers.read().await;
+	let item_buffers = go.item_buffers.snapshot();
 	#[derive(Serialize, Debug)]
 	struct ItemFrequency {
 		id: String,
@@ -145,7 +396,7 @@ async fn item_frequency(State(go): State<Arc<GlobalObject>>) -> Response {
 	}
 }
 async fn fluid_frequency(State(go): State<Arc<GlobalObject>>) -> Response {
-	let fluid_buffers = go.fluid_buffers.read().await;
+	let fluid_buffers = go.fluid_buffers.snapshot();
 	#[derive(Serialize, Debug)]
 	struct FluidFrequency {
 		id: String,
@@ -169,24 +420,25 @@ async fn fluid_frequency(State(go): State<Arc<GlobalObject>>) -> Response {
 	}
 }
 async fn energy_frequency(State(go): State<Arc<GlobalObject>>) -> Response {
-	let energy_buffers = go.energy_buffers.read().await;
+	let config = go.config.read().await;
+	let energy_buffers = go.energy_buffers.snapshot();
 	#[derive(Serialize, Debug)]
 	struct EnergyFrequency {
 		id: String,
 		value: i64,
+		capacity: i64,
+		loss: i64,
 	}
-	let values = {
-		let jobs = energy_buffers.iter().map(|(freq, value)| async {
-			EnergyFrequency {
-				id: freq.0.clone(),
-				value: *value,
-			}
-		});
-		futures::future::join_all(jobs)
-			.await
-			.into_iter()
-			.collect::<Vec<_>>()
-	};
+	let values = energy_buffers
+		.iter()
+		.map(|(freq, energy)| EnergyFrequency {
+			id: freq.0.clone(),
+			value: energy.value(),
+			capacity: energy_capacity(&config, freq),
+			loss: energy.loss(),
+		})
+		.filter(|v| v.value != 0 || v.loss != 0)
+		.collect::<Vec<_>>();
 	match serde_json::to_string(&values) {
 		Ok(json) => (StatusCode::OK, json).into_response(),
 		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
@@ -196,15 +448,19 @@ async fn clients(State(go): State<Arc<GlobalObject>>) -> Response {
 	let clients = go.clients.read().await;
 	#[derive(Serialize, Debug)]
 	struct ClientMeta {
+		id: String,
 		name: String,
 		sync: i64,
+		stats: crate::client::ClientStats,
 	}
 	let clients = {
-		let jobs = clients.iter().map(|(_id, meta)| async {
+		let jobs = clients.values().map(|meta| async {
 			let meta = meta.lock().await;
 			ClientMeta {
+				id: meta.id.to_string(),
 				name: meta.hostname.clone(),
 				sync: meta.last_sync_time,
+				stats: meta.stats(),
 			}
 		});
 		futures::future::join_all(jobs)
@@ -218,9 +474,75 @@ async fn clients(State(go): State<Arc<GlobalObject>>) -> Response {
 	}
 }
 
-async fn shutdown_signal() {
+#[derive(Debug, Deserialize)]
+struct ParmHistory {
+	/// 未指定は全周波数の合計
+	frequency: Option<String>,
+	/// UNIXミリ秒
+	#[serde(default)]
+	since: i64,
+}
+async fn history(
+	State(go): State<Arc<GlobalObject>>,
+	Query(params): Query<ParmHistory>,
+) -> Response {
+	#[derive(Serialize, Debug)]
+	struct Point {
+		time: i64,
+		#[serde(flatten)]
+		level: crate::history::Level,
+	}
+	let points = go
+		.history
+		.query(params.frequency.as_deref(), params.since)
+		.into_iter()
+		.map(|(time, level)| Point { time, level })
+		.collect::<Vec<_>>();
+	json_response(&points)
+}
+/// 全周波数からアイテム・液体を探す
+async fn search(
+	State(go): State<Arc<GlobalObject>>,
+	Query(params): Query<crate::search::SearchQuery>,
+) -> Response {
+	json_response(&go.search(&params).await)
+}
+/// 全周波数の合計
+async fn summary(State(go): State<Arc<GlobalObject>>) -> Response {
+	json_response(&go.summary().await)
+}
+/// HTTPが応答できれば生きている
+async fn healthz() -> Response {
+	json_response(&serde_json::json!({ "status": "ok" }))
+}
+async fn readyz(State(go): State<Arc<GlobalObject>>) -> Response {
+	let readiness = go.readiness();
+	let status = if readiness.ready {
+		StatusCode::OK
+	} else {
+		StatusCode::SERVICE_UNAVAILABLE
+	};
+	(status, json_response(&readiness)).into_response()
+}
+async fn metrics(State(go): State<Arc<GlobalObject>>) -> Response {
+	let headers = [(header::CONTENT_TYPE, "text/plain; version=0.0.4")];
+	(StatusCode::OK, headers, go.render_metrics().await).into_response()
+}
+fn json_response<T: Serialize>(value: &T) -> Response {
+	match serde_json::to_string(value) {
+		Ok(json) => (StatusCode::OK, json).into_response(),
+		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+	}
+}
+
+async fn shutdown_signal(go: Arc<GlobalObject>) {
 	use futures::{future::FutureExt, pin_mut};
 	use tokio::signal;
+	let mut shutdown = go.shutdown.subscribe();
+	let requested = async move {
+		let _ = shutdown.wait_for(|stop| *stop).await;
+	}
+	.fuse();
 	let ctrl_c = async {
 		signal::ctrl_c()
 			.await
@@ -238,9 +560,175 @@ async fn shutdown_signal() {
 	.fuse();
 	#[cfg(not(unix))]
 	let terminate = std::future::pending::<()>().fuse();
-	pin_mut!(ctrl_c, terminate);
+	pin_mut!(ctrl_c, terminate, requested);
 	futures::select! {
 		_ = ctrl_c => {},
 		_ = terminate => {},
+		_ = requested => {},
+	}
+	//イベントの配信を終わらせないと接続が閉じない
+	go.shutdown.send_replace(true);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::Arc, time::Duration};
+
+	use axum::{
+		extract::{Query, State},
+		http::StatusCode,
+	};
+
+	use super::NbtFormat;
+	use crate::{
+		item::{ItemStack, NBT},
+		Frequency, GlobalObject,
+	};
+
+	/// 集計中の周波数が書き込み中でも、ダッシュボードのポーリングが新しい周波数の作成を止めない
+	///
+	/// 1000周波数を作るので`--ignored`で実行する
+	#[test]
+	#[ignore]
+	fn polling_does_not_stall_clients() {
+		tokio::runtime::Builder::new_multi_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::dummy().await);
+				let busy = go.items(&Frequency("RED, RED, RED".into()));
+				let guard = busy.data.write().await;
+				let pollers = (0..32)
+					.map(|_| {
+						let go = go.clone();
+						tokio::spawn(async move {
+							let res = super::item_frequency(State(go)).await;
+							assert_eq!(res.status(), StatusCode::OK);
+						})
+					})
+					.collect::<Vec<_>>();
+				tokio::time::sleep(Duration::from_millis(50)).await;
+				for i in 0..1000 {
+					let job = async {
+						go.items(&Frequency(format!("NEW {}", i)))
+							.insert_items(&mut vec![ItemStack::dummy()])
+							.await;
+					};
+					tokio::time::timeout(Duration::from_secs(1), job)
+						.await
+						.expect("item_recv stalled by dashboard polling");
+				}
+				drop(guard);
+				for poller in pollers {
+					poller.await.unwrap();
+				}
+				assert_eq!(go.item_buffers.snapshot().len(), 1002);
+			});
+	}
+	#[test]
+	fn item_detail() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::dummy().await);
+				let freq = Frequency("WHITE, BLUE, WHITE".into());
+				let id = go.items(&freq).data.read().await[0].meta.id;
+				let params = |id| super::ParmItem {
+					frequency: freq.0.clone(),
+					id,
+				};
+				let res = super::item(State(go.clone()), Query(params(id))).await;
+				assert_eq!(res.status(), StatusCode::OK);
+				let res = super::item(State(go.clone()), Query(params(0))).await;
+				assert_eq!(res.status(), StatusCode::NOT_FOUND);
+				let nbt = |format| super::ParmItemNbt {
+					item: params(id),
+					format,
+				};
+				let res = super::item_nbt(State(go.clone()), Query(nbt(NbtFormat::Binary))).await;
+				assert_eq!(res.status(), StatusCode::OK);
+				let body = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+				let gz = go.items(&freq).data.read().await[0].nbt.clone();
+				match gz {
+					Some(NBT::Extra(Some(gz))) => assert_eq!(&body.unwrap()[..], gz.as_gzip()),
+					_ => panic!("heavy_dummy has no gzip nbt"),
+				}
+				let res = super::item_nbt(State(go.clone()), Query(nbt(NbtFormat::Raw))).await;
+				assert_eq!(res.status(), StatusCode::OK);
+				//一覧で展開したNBTは覚えておく
+				let uri = "/?frequency=WHITE,%20BLUE,%20WHITE".parse().unwrap();
+				let params = Query::try_from_uri(&uri).unwrap();
+				let res = super::items(State(go.clone()), params).await;
+				assert_eq!(res.status(), StatusCode::OK);
+				let nbt = go.items(&freq).data.read().await[0].nbt.clone();
+				let Some(NBT::Extra(Some(gz))) = nbt else {
+					unreachable!()
+				};
+				assert!(go.nbt_display.get(gz.hash()).is_some());
+			});
+	}
+	#[test]
+	fn items_query() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::dummy().await);
+				let freq = Frequency("BLUE".into());
+				let mut stacks = ["minecraft:stone", "mekanism:ingot", "minecraft:dirt"]
+					.into_iter()
+					.chain(["minecraft:stone"; 2])
+					.enumerate()
+					.map(|(i, id)| ItemStack {
+						count: i as i32 + 1,
+						..ItemStack::dummy_with_id(id)
+					})
+					.collect();
+				go.items(&freq).insert_items(&mut stacks).await;
+				let query = |query: &str| {
+					let go = go.clone();
+					let uri = format!("/?frequency=BLUE&{}", query).parse().unwrap();
+					async move {
+						let params = Query::try_from_uri(&uri).unwrap();
+						let res = super::items(State(go), params).await;
+						assert_eq!(res.status(), StatusCode::OK);
+						let total = res.headers()["x-total-count"].to_str().unwrap().to_owned();
+						let body = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+						let body: serde_json::Value =
+							serde_json::from_slice(&body.unwrap()).unwrap();
+						(total.parse::<usize>().unwrap(), body)
+					}
+				};
+				let names = |body: &serde_json::Value| {
+					let items = body.as_array().unwrap().iter();
+					items
+						.map(|item| format!("{}x{}", item["name"].as_str().unwrap(), item["count"]))
+						.collect::<Vec<_>>()
+				};
+				let (total, body) = query("").await;
+				assert_eq!(total, 5);
+				assert_eq!(names(&body)[0], "minecraft:stonex1");
+				let (total, body) = query("q=STONE&sort=count&limit=2").await;
+				assert_eq!(total, 3);
+				assert_eq!(names(&body), ["minecraft:stonex5", "minecraft:stonex4"]);
+				let (_, body) = query("modid=minecraft&sort=name&order=desc&offset=1").await;
+				assert_eq!(
+					names(&body),
+					["minecraft:stonex4", "minecraft:stonex5", "minecraft:dirtx3"]
+				);
+				let (total, body) = query("aggregate=true&sort=modid").await;
+				assert_eq!(total, 3);
+				assert_eq!(
+					names(&body),
+					["mekanism:ingotx2", "minecraft:dirtx3", "minecraft:stonex10"]
+				);
+				assert_eq!(body[2]["stacks"], 3);
+				let (total, _) = query("q=diamond").await;
+				assert_eq!(total, 0);
+			});
 	}
 }
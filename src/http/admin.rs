@@ -0,0 +1,188 @@
+//! 標準入力を使えない環境向けの管理API
+//!
+//! 処理は`cli`のコマンドと共通 認証は`config.json`の`admin_token`
+
+use std::sync::Arc;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use serde::Deserialize;
+
+use super::{json_response, write::authorize, write::Rejection};
+use crate::{audit, cli, config, logging, Frequency, GlobalObject};
+
+async fn authorize_admin(go: &GlobalObject, headers: &HeaderMap) -> Result<(), Rejection> {
+	let config = go.config.read().await;
+	authorize(headers, config.admin_token.as_deref())
+}
+fn done(result: Result<(), tokio::io::Error>) -> Response {
+	match result {
+		Ok(()) => json_response(&serde_json::json!({ "ok": true })),
+		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+	}
+}
+/// 搬入出の記録 クライアント名や量が分かるので管理者のみ
+pub(super) async fn audit(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Query(params): Query<audit::AuditQuery>,
+) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	let path = audit::audit_file(&*go.config.read().await);
+	match audit::query(&path, &params).await {
+		Ok(entries) => json_response(&entries),
+		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+	}
+}
+/// `?force=true`で起動時の読み込みに失敗していても保存する
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct Force {
+	#[serde(default)]
+	force: bool,
+}
+pub(super) async fn save(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Query(params): Query<Force>,
+) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	done(cli::save_file(cli::SAVE_FILE, &go, params.force).await)
+}
+pub(super) async fn load(State(go): State<Arc<GlobalObject>>, headers: HeaderMap) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	done(cli::load_file(cli::SAVE_FILE, &go).await)
+}
+pub(super) async fn reload(State(go): State<Arc<GlobalObject>>, headers: HeaderMap) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	done(cli::reload_config(config::CONFIG_FILE, &go).await)
+}
+/// 保存に成功すれば応答を返してから終了する
+pub(super) async fn stop(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Query(params): Query<Force>,
+) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	done(cli::stop(&go, params.force).await)
+}
+#[derive(Debug, Deserialize)]
+pub(super) struct ClearRequest {
+	frequency: String,
+}
+pub(super) async fn clear(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<ClearRequest>,
+) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	let freq = Frequency(req.frequency);
+	json_response(&cli::clear_frequency(&go, &freq, "admin").await)
+}
+#[derive(Debug, Deserialize)]
+pub(super) struct MoveRequest {
+	from: String,
+	to: String,
+}
+pub(super) async fn move_contents(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<MoveRequest>,
+) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	let (from, to) = (Frequency(req.from), Frequency(req.to));
+	match cli::move_frequency(&go, &from, &to, "admin").await {
+		Ok(moved) => json_response(&moved),
+		Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+	}
+}
+#[derive(Debug, Deserialize)]
+pub(super) struct LogRequest {
+	level: String,
+}
+/// 設定ファイルを書き換えずにログレベルを変える 再読み込みすると設定の値に戻る
+pub(super) async fn log_level(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<LogRequest>,
+) -> Response {
+	if let Err(res) = authorize_admin(&go, &headers).await {
+		return res.into_response();
+	}
+	match logging::set_level(&req.level) {
+		Ok(()) => json_response(&serde_json::json!({ "ok": true })),
+		Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use axum::{
+		extract::{Query, State},
+		http::{header, HeaderMap, StatusCode},
+		Json,
+	};
+
+	use crate::{Frequency, GlobalObject};
+
+	#[test]
+	fn admin_api() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::dummy().await);
+				go.config.write().await.api_token = Some("api".into());
+				go.config.write().await.admin_token = Some("admin".into());
+				let headers = |token: &str| {
+					let mut headers = HeaderMap::new();
+					let value = format!("Bearer {}", token).parse().unwrap();
+					headers.insert(header::AUTHORIZATION, value);
+					headers
+				};
+				let clear = |token| {
+					let req = serde_json::json!({ "frequency": "RED, RED, RED" });
+					let req = serde_json::from_value(req).unwrap();
+					super::clear(State(go.clone()), headers(token), Json(req))
+				};
+				//搬入出用のトークンでは管理操作できない
+				assert_eq!(clear("api").await.status(), StatusCode::UNAUTHORIZED);
+				assert_eq!(go.items(&Frequency("RED, RED, RED".into())).len().await, 1);
+				assert_eq!(clear("admin").await.status(), StatusCode::OK);
+				assert_eq!(go.items(&Frequency("RED, RED, RED".into())).len().await, 0);
+				let req = serde_json::json!({ "from": "RED", "to": "RED" });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::move_contents(State(go.clone()), headers("admin"), Json(req));
+				assert_eq!(res.await.status(), StatusCode::BAD_REQUEST);
+				//監査ログも管理者のみ
+				let missing = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+				go.config.write().await.audit_file = Some(missing.to_string_lossy().into());
+				let audit = |token| {
+					let params = Query(Default::default());
+					super::audit(State(go.clone()), headers(token), params)
+				};
+				assert_eq!(audit("api").await.status(), StatusCode::UNAUTHORIZED);
+				assert_eq!(audit("admin").await.status(), StatusCode::OK);
+			});
+	}
+}
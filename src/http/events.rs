@@ -0,0 +1,107 @@
+//! ダッシュボード向けの変更通知(Server-Sent Events)
+//!
+//! 短い間隔でまとめ、同じ周波数・クライアントの通知は最新のものだけ送る
+
+use std::{collections::HashSet, convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+	extract::State,
+	response::sse::{Event as SseEvent, KeepAlive, Sse},
+};
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+use crate::{events::Event, GlobalObject};
+
+const BATCH_MILLIS: u64 = 200;
+
+pub(super) async fn events(
+	State(go): State<Arc<GlobalObject>>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+	let rx = go.events.subscribe();
+	let stream = futures::stream::unfold(rx, |mut rx| async move {
+		let first = match rx.recv().await {
+			Ok(event) => event,
+			Err(RecvError::Lagged(_)) => return Some((Ok(resync()), rx)),
+			Err(RecvError::Closed) => return None,
+		};
+		tokio::time::sleep(Duration::from_millis(BATCH_MILLIS)).await;
+		let mut batch = vec![first];
+		loop {
+			match rx.try_recv() {
+				Ok(event) => batch.push(event),
+				Err(TryRecvError::Lagged(_)) => return Some((Ok(resync()), rx)),
+				Err(_) => break,
+			}
+		}
+		Some((Ok(update(&coalesce(batch))), rx))
+	});
+	let mut shutdown = go.shutdown.subscribe();
+	let shutdown = async move {
+		let _ = shutdown.wait_for(|stop| *stop).await;
+	};
+	Sse::new(stream.take_until(Box::pin(shutdown))).keep_alive(KeepAlive::default())
+}
+/// 取りこぼしたので一覧を取得し直させる
+fn resync() -> SseEvent {
+	SseEvent::default().event("resync").data("")
+}
+fn update(batch: &[Event]) -> SseEvent {
+	let json = serde_json::to_string(batch).unwrap_or_else(|_| "[]".into());
+	SseEvent::default().event("update").data(json)
+}
+/// キーごとに最後の通知だけを残す 順序は最後の通知の順
+fn coalesce(batch: Vec<Event>) -> Vec<Event> {
+	let mut seen = HashSet::new();
+	let mut events = batch
+		.into_iter()
+		.rev()
+		.filter(|event| {
+			let (kind, id) = event.key();
+			seen.insert((kind, id.to_owned()))
+		})
+		.collect::<Vec<_>>();
+	events.reverse();
+	events
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{events::Event, item::ItemStack, Frequency, GlobalObject};
+
+	#[test]
+	fn notify_changes() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let mut rx = go.events.subscribe();
+				let freq = Frequency("RED, RED, RED".into());
+				let items = go.items(&freq);
+				items.insert_items(&mut vec![ItemStack::dummy()]).await;
+				items.insert_items(&mut vec![ItemStack::dummy()]).await;
+				go.energy_insert(&freq, 100).await;
+				let mut batch = Vec::new();
+				while let Ok(event) = rx.try_recv() {
+					batch.push(event);
+				}
+				assert_eq!(batch.len(), 3);
+				assert_eq!(
+					super::coalesce(batch),
+					[
+						Event::ItemFrequency {
+							id: freq.0.clone(),
+							size: 3
+						},
+						Event::Energy {
+							id: freq.0.clone(),
+							value: 100,
+							loss: 0
+						}
+					]
+				);
+			});
+	}
+}
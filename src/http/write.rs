@@ -0,0 +1,514 @@
+//! 外部の自動化ツールから搬入出するためのAPI
+//!
+//! TCPクライアントと同じ`Items`・`Fluids`・エネルギーの処理を通す
+//! 認証は`config.json`の`api_token`を`Authorization: Bearer`で送る
+
+use std::sync::Arc;
+
+use axum::{
+	extract::State,
+	http::{header, HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::json_response;
+use crate::{
+	audit::{Direction, Kind},
+	fluid::FluidStack,
+	from_hex_string,
+	item::{ItemStack, NBT},
+	meta::StackMeta,
+	nbt_store::NbtStore,
+	to_hex_string, Frequency, GlobalObject,
+};
+
+pub(super) type Rejection = (StatusCode, String);
+
+/// `token`が未設定なら常に拒否する
+pub(super) fn authorize(headers: &HeaderMap, token: Option<&str>) -> Result<(), Rejection> {
+	let Some(token) = token else {
+		return Err((StatusCode::FORBIDDEN, "api disabled".to_owned()));
+	};
+	let bearer = headers
+		.get(header::AUTHORIZATION)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.strip_prefix("Bearer "));
+	//比較にかかる時間からトークンを推測させない
+	let matched = bearer.is_some_and(|bearer| {
+		bearer.len() == token.len()
+			&& bearer
+				.bytes()
+				.zip(token.bytes())
+				.fold(0, |acc, (a, b)| acc | (a ^ b))
+				== 0
+	});
+	if matched {
+		Ok(())
+	} else {
+		Err((StatusCode::UNAUTHORIZED, "invalid token".to_owned()))
+	}
+}
+/// HTTPからの搬入出はクライアント"http"として記録する
+fn audit(
+	go: &GlobalObject,
+	freq: &Frequency,
+	kind: Kind,
+	direction: Direction,
+	id: Option<&str>,
+	count: i64,
+) {
+	go.record_audit("http", freq, kind, direction, id, count);
+}
+async fn authorize_api(go: &GlobalObject, headers: &HeaderMap) -> Result<(), Rejection> {
+	let config = go.config.read().await;
+	authorize(headers, config.api_token.as_deref())
+}
+fn bad_request(message: &str) -> Rejection {
+	(StatusCode::BAD_REQUEST, message.to_owned())
+}
+/// NBTは展開済みのバイナリを16進数で表す
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct ItemJson {
+	name: String,
+	count: i32,
+	#[serde(default)]
+	damage: i32,
+	#[serde(default)]
+	nbt: Option<String>,
+}
+impl ItemJson {
+	async fn to_stack(&self, store: &NbtStore) -> Result<ItemStack, Rejection> {
+		if self.count <= 0 {
+			return Err(bad_request("count must be positive"));
+		}
+		let nbt = match &self.nbt {
+			Some(hex) => {
+				let raw = from_hex_string(hex).ok_or_else(|| bad_request("invalid nbt"))?;
+				let nbt = NBT::from_raw(raw).await;
+				Some(nbt.map_err(|e| bad_request(&e.to_string()))?)
+			}
+			None => None,
+		};
+		let mut is = ItemStack {
+			damage: self.damage,
+			count: self.count,
+			id: self.name.clone(),
+			nbt,
+			meta: StackMeta::default(),
+		};
+		is.share_nbt(store)
+			.map_err(|e| bad_request(&e.to_string()))?;
+		Ok(is)
+	}
+	async fn from_stack(is: &ItemStack) -> Result<Self, tokio::io::Error> {
+		let nbt = match &is.nbt {
+			Some(nbt) => nbt.to_raw().await?,
+			None => None,
+		};
+		Ok(Self {
+			name: is.id.clone(),
+			count: is.count,
+			damage: is.damage,
+			nbt: nbt.map(|raw| to_hex_string(&raw)),
+		})
+	}
+}
+#[derive(Debug, Deserialize)]
+pub(super) struct ItemInsert {
+	frequency: String,
+	items: Vec<ItemJson>,
+}
+#[derive(Debug, Serialize)]
+struct ItemInsertResult {
+	inserted: usize,
+	/// バッファが一杯で入らなかったスタック数 リクエストの末尾から数える
+	rejected: usize,
+}
+pub(super) async fn item_insert(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<ItemInsert>,
+) -> Response {
+	if let Err(res) = authorize_api(&go, &headers).await {
+		return res.into_response();
+	}
+	let mut stacks = Vec::new();
+	for item in &req.items {
+		match item.to_stack(&go.nbt_store).await {
+			Ok(is) => stacks.push(is),
+			Err(res) => return res.into_response(),
+		}
+	}
+	let total = stacks.len();
+	let freq = Frequency(req.frequency);
+	let offered = stacks
+		.iter()
+		.map(|is| (is.id.clone(), is.count))
+		.collect::<Vec<_>>();
+	go.items(&freq).insert_items(&mut stacks).await;
+	go.metrics.rejected_items(stacks.len());
+	for (id, count) in &offered[..total - stacks.len()] {
+		audit(
+			&go,
+			&freq,
+			Kind::Item,
+			Direction::Insert,
+			Some(id),
+			*count as i64,
+		);
+	}
+	json_response(&ItemInsertResult {
+		inserted: total - stacks.len(),
+		rejected: stacks.len(),
+	})
+}
+fn audit_take(go: &GlobalObject, freq: &Frequency, is: &ItemStack) {
+	let count = is.count as i64;
+	audit(go, freq, Kind::Item, Direction::Take, Some(&is.id), count);
+}
+#[derive(Debug, Deserialize)]
+pub(super) struct ItemTake {
+	frequency: String,
+	max_stacks: i32,
+}
+#[derive(Debug, Serialize)]
+struct ItemTakeResult {
+	items: Vec<ItemJson>,
+	/// NBTを展開できなかったスタック 詰まらないよう取り出して報告する
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	broken: Vec<BrokenItemJson>,
+}
+/// 展開できなかったNBTはgzipのまま16進数で返す
+#[derive(Debug, Serialize)]
+struct BrokenItemJson {
+	name: String,
+	count: i32,
+	damage: i32,
+	nbt_gzip: Option<String>,
+	error: String,
+}
+impl BrokenItemJson {
+	fn from_stack(is: &ItemStack, e: &tokio::io::Error) -> Self {
+		let nbt_gzip = match &is.nbt {
+			Some(NBT::Extra(Some(gz))) => Some(to_hex_string(gz.as_gzip())),
+			_ => None,
+		};
+		Self {
+			name: is.id.clone(),
+			count: is.count,
+			damage: is.damage,
+			nbt_gzip,
+			error: e.to_string(),
+		}
+	}
+}
+pub(super) async fn item_take(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<ItemTake>,
+) -> Response {
+	if let Err(res) = authorize_api(&go, &headers).await {
+		return res.into_response();
+	}
+	let freq = Frequency(req.frequency);
+	let Some(freq_buffer) = go.item_buffers.get(&freq) else {
+		return json_response(&ItemTakeResult {
+			items: Vec::new(),
+			broken: Vec::new(),
+		});
+	};
+	let order = go.config.read().await.frequency(&freq).item_order;
+	let taken = freq_buffer.take_items(req.max_stacks, &order).await;
+	let mut items = Vec::new();
+	let mut broken = Vec::new();
+	for is in &taken {
+		match ItemJson::from_stack(is).await {
+			Ok(item) => items.push(item),
+			Err(e) => {
+				//戻すと毎回先頭で失敗して搬出が止まるので、取り出したまま報告する
+				tracing::warn!(error = %e, frequency = freq.0, id = is.id, "broken nbt");
+				broken.push(BrokenItemJson::from_stack(is, &e));
+			}
+		}
+		audit_take(&go, &freq, is);
+	}
+	json_response(&ItemTakeResult { items, broken })
+}
+/// NBTはバイナリを16進数で表す
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct FluidJson {
+	/// 搬出時に空なら種類を問わない
+	#[serde(default)]
+	name: String,
+	count: i64,
+	#[serde(default)]
+	nbt: Option<String>,
+}
+impl FluidJson {
+	fn to_stack(&self) -> Result<FluidStack, Rejection> {
+		if self.count <= 0 {
+			return Err(bad_request("count must be positive"));
+		}
+		let nbt = match &self.nbt {
+			Some(hex) => Some(from_hex_string(hex).ok_or_else(|| bad_request("invalid nbt"))?),
+			None => None,
+		};
+		Ok(FluidStack::new(self.name.clone(), self.count, nbt))
+	}
+	fn from_stack(fs: &FluidStack) -> Self {
+		Self {
+			name: fs.name.clone(),
+			count: fs.count,
+			nbt: fs.nbt.as_ref().map(|nbt| to_hex_string(nbt)),
+		}
+	}
+}
+#[derive(Debug, Deserialize)]
+pub(super) struct FluidRequest {
+	frequency: String,
+	#[serde(flatten)]
+	fluid: FluidJson,
+}
+#[derive(Debug, Serialize)]
+struct FluidResult {
+	fluid: Option<FluidJson>,
+}
+pub(super) async fn fluid_insert(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<FluidRequest>,
+) -> Response {
+	if let Err(res) = authorize_api(&go, &headers).await {
+		return res.into_response();
+	}
+	if req.fluid.name.is_empty() {
+		return bad_request("name is required").into_response();
+	}
+	let fs = match req.fluid.to_stack() {
+		Ok(fs) => fs,
+		Err(res) => return res.into_response(),
+	};
+	let freq = Frequency(req.frequency);
+	go.fluids(&freq).insert_fluid(fs.clone()).await;
+	audit(
+		&go,
+		&freq,
+		Kind::Fluid,
+		Direction::Insert,
+		Some(&fs.name),
+		fs.count,
+	);
+	json_response(&FluidResult {
+		fluid: Some(FluidJson::from_stack(&fs)),
+	})
+}
+pub(super) async fn fluid_take(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<FluidRequest>,
+) -> Response {
+	if let Err(res) = authorize_api(&go, &headers).await {
+		return res.into_response();
+	}
+	let fs = match req.fluid.to_stack() {
+		Ok(fs) => fs,
+		Err(res) => return res.into_response(),
+	};
+	let freq = Frequency(req.frequency);
+	let taken = match go.fluid_buffers.get(&freq) {
+		Some(freq_buffer) => freq_buffer.take_fluid(fs).await,
+		None => None,
+	};
+	if let Some(fs) = &taken {
+		audit(
+			&go,
+			&freq,
+			Kind::Fluid,
+			Direction::Take,
+			Some(&fs.name),
+			fs.count,
+		);
+	}
+	json_response(&FluidResult {
+		fluid: taken.as_ref().map(FluidJson::from_stack),
+	})
+}
+#[derive(Debug, Deserialize)]
+pub(super) struct EnergyRequest {
+	frequency: String,
+	amount: i64,
+}
+#[derive(Debug, Serialize)]
+struct EnergyInsertResult {
+	accepted: i64,
+	rejected: i64,
+}
+#[derive(Debug, Serialize)]
+struct EnergyTakeResult {
+	taken: i64,
+}
+pub(super) async fn energy_insert(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<EnergyRequest>,
+) -> Response {
+	if let Err(res) = authorize_api(&go, &headers).await {
+		return res.into_response();
+	}
+	if req.amount < 0 {
+		return bad_request("amount must not be negative").into_response();
+	}
+	let freq = Frequency(req.frequency);
+	let rejected = go.energy_insert(&freq, req.amount).await;
+	audit(
+		&go,
+		&freq,
+		Kind::Energy,
+		Direction::Insert,
+		None,
+		req.amount - rejected,
+	);
+	json_response(&EnergyInsertResult {
+		accepted: req.amount - rejected,
+		rejected,
+	})
+}
+pub(super) async fn energy_take(
+	State(go): State<Arc<GlobalObject>>,
+	headers: HeaderMap,
+	Json(req): Json<EnergyRequest>,
+) -> Response {
+	if let Err(res) = authorize_api(&go, &headers).await {
+		return res.into_response();
+	}
+	if req.amount < 0 {
+		return bad_request("amount must not be negative").into_response();
+	}
+	let freq = Frequency(req.frequency);
+	let taken = go.energy_take(&freq, req.amount).await;
+	audit(&go, &freq, Kind::Energy, Direction::Take, None, taken);
+	json_response(&EnergyTakeResult { taken })
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use axum::{
+		extract::State,
+		http::{header, HeaderMap, StatusCode},
+		Json,
+	};
+
+	use crate::{
+		config::Config,
+		item::{GzipNBT, ItemStack, NBT},
+		to_hex_string, Frequency, GlobalObject,
+	};
+
+	fn headers(token: &str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		let value = format!("Bearer {}", token).parse().unwrap();
+		headers.insert(header::AUTHORIZATION, value);
+		headers
+	}
+	#[test]
+	fn write_api() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let config: Config = serde_json::from_str(r#"{ "api_token": "secret" }"#).unwrap();
+				let go = Arc::new(GlobalObject::new(config));
+				let insert = |token: &str| {
+					let req = serde_json::from_value(serde_json::json!({
+						"frequency": "RED",
+						"items": [{ "name": "minecraft:stone", "count": 64, "nbt": "0A000000" }]
+					}));
+					super::item_insert(State(go.clone()), headers(token), Json(req.unwrap()))
+				};
+				assert_eq!(insert("wrong").await.status(), StatusCode::UNAUTHORIZED);
+				assert!(go.item_buffers.get(&Frequency("RED".into())).is_none());
+				assert_eq!(insert("secret").await.status(), StatusCode::OK);
+				let req = serde_json::json!({ "frequency": "RED", "max_stacks": 10 });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::item_take(State(go.clone()), headers("secret"), Json(req)).await;
+				let body = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+				let body: serde_json::Value = serde_json::from_slice(&body.unwrap()).unwrap();
+				assert_eq!(
+					body,
+					serde_json::json!({ "items": [
+						{ "name": "minecraft:stone", "count": 64, "damage": 0, "nbt": "0A000000" }
+					]})
+				);
+
+				let req = serde_json::json!({ "frequency": "RED", "amount": 100 });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::energy_insert(State(go.clone()), headers("secret"), Json(req));
+				assert_eq!(res.await.status(), StatusCode::OK);
+				assert_eq!(go.energy_value(&Frequency("RED".into())), 100);
+				let req = serde_json::json!({ "frequency": "RED", "amount": 30 });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::energy_take(State(go.clone()), headers("secret"), Json(req));
+				assert_eq!(res.await.status(), StatusCode::OK);
+				assert_eq!(go.energy_value(&Frequency("RED".into())), 70);
+
+				let req = serde_json::json!({ "frequency": "RED", "name": "water", "count": 1000 });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::fluid_insert(State(go.clone()), headers("secret"), Json(req));
+				assert_eq!(res.await.status(), StatusCode::OK);
+				let req = serde_json::json!({ "frequency": "RED", "count": 400 });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::fluid_take(State(go.clone()), headers("secret"), Json(req));
+				assert_eq!(res.await.status(), StatusCode::OK);
+				let fluids = go.fluids(&Frequency("RED".into())).snapshot().await;
+				assert_eq!(fluids[0].count, 600);
+			});
+	}
+	#[test]
+	fn item_take_error() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let config: Config = serde_json::from_str(r#"{ "api_token": "secret" }"#).unwrap();
+				let go = Arc::new(GlobalObject::new(config));
+				let freq = Frequency("RED".into());
+				let broken = ItemStack {
+					nbt: Some(NBT::Extra(Some(GzipNBT::from_gzip(b"not gzip".to_vec())))),
+					..ItemStack::dummy()
+				};
+				let mut stacks = vec![ItemStack::dummy(), broken];
+				go.items(&freq).insert_items(&mut stacks).await;
+				let req = serde_json::json!({ "frequency": "RED", "max_stacks": 10 });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::item_take(State(go.clone()), headers("secret"), Json(req)).await;
+				assert_eq!(res.status(), StatusCode::OK);
+				let body = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+				let body: serde_json::Value = serde_json::from_slice(&body.unwrap()).unwrap();
+				//壊れたスタックがあっても他は取り出せ、壊れたものはgzipのまま返す
+				assert_eq!(body["items"].as_array().unwrap().len(), 1);
+				let broken = &body["broken"][0];
+				assert_eq!(broken["nbt_gzip"], to_hex_string(b"not gzip"));
+				assert!(go.items(&freq).data.read().await.is_empty());
+			});
+	}
+	#[test]
+	fn api_disabled() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::new(Config::default()));
+				let req = serde_json::json!({ "frequency": "RED", "amount": 100 });
+				let req = serde_json::from_value(req).unwrap();
+				let res = super::energy_insert(State(go.clone()), headers(""), Json(req));
+				assert_eq!(res.await.status(), StatusCode::FORBIDDEN);
+			});
+	}
+}
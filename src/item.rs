@@ -1,41 +1,198 @@
 use std::sync::Arc;
 
+use serde::Deserialize;
 use tokio::{
 	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 	sync::RwLock,
 };
 
-use crate::{client::ClientSession, read_string, to_hex_string, write_string};
+use crate::{
+	audit::{Direction, Kind},
+	client::ClientSession,
+	events::Notifier,
+	fair::Resource,
+	meta::StackMeta,
+	nbt::Tag,
+	nbt_store::{self, NbtHash, NbtStore},
+	read_string, to_hex_string, write_string, Frequency, GlobalObject,
+};
 
 const ITEM_BUFFER_LIMIT: usize = 100;
+/// 展開後のNBTの上限 gzip爆弾で展開し続けないようにする
+pub(crate) const MAX_NBT_BYTES: u64 = 16 * 1024 * 1024;
+/// `read_extra`で長さの代わりにこの値が来たら続く16バイトはNBTのハッシュ
+const EXTRA_HASH_FLAG: i32 = -1;
 
 #[derive(Clone, Debug)]
 pub struct Items {
 	pub(crate) data: Arc<RwLock<Vec<ItemStack>>>,
+	notifier: Option<Notifier>,
 }
 impl Items {
 	pub(crate) fn new() -> Self {
 		Self {
 			data: Arc::new(RwLock::new(Vec::new())),
+			notifier: None,
+		}
+	}
+	pub(crate) fn with_notifier(mut self, notifier: Notifier) -> Self {
+		self.notifier = Some(notifier);
+		self
+	}
+	/// `data`を直接書き換えた後に呼ぶ
+	pub(crate) fn changed(&self, len: usize) {
+		if let Some(notifier) = &self.notifier {
+			notifier.items(len);
 		}
 	}
-	pub async fn take_items(&self, max_stacks: i32) -> Vec<ItemStack> {
+	pub async fn take_items(&self, max_stacks: i32, order: &ItemOrder) -> Vec<ItemStack> {
 		let mut data = self.data.write().await;
 		let max_stacks = data.len().min(max_stacks.max(0) as usize);
-		let stacks = data.drain(0..max_stacks);
-		stacks.collect()
+		let indices = order.select(&data, max_stacks);
+		let mut slots = std::mem::take(&mut *data)
+			.into_iter()
+			.map(Some)
+			.collect::<Vec<_>>();
+		let stacks: Vec<_> = indices.iter().filter_map(|i| slots[*i].take()).collect();
+		*data = slots.into_iter().flatten().collect();
+		if !stacks.is_empty() {
+			self.changed(data.len());
+		}
+		stacks
 	}
 	pub async fn insert_items(&self, stacks: &mut Vec<ItemStack>) {
 		let mut data = self.data.write().await;
 		let max_stacks = stacks
 			.len()
 			.min(ITEM_BUFFER_LIMIT.saturating_sub(data.len()));
-		let stacks = stacks.drain(0..max_stacks);
-		data.append(&mut stacks.collect());
+		let meta = StackMeta::now();
+		let stacks = stacks.drain(0..max_stacks).map(|mut is| {
+			is.meta = meta.renew();
+			is
+		});
+		data.extend(stacks);
+		if max_stacks > 0 {
+			self.changed(data.len());
+		}
+	}
+	/// 別の周波数から搬入時刻と番号を変えずに末尾へ移す 入りきらない分は`stacks`に残る
+	pub async fn move_items(&self, stacks: &mut Vec<ItemStack>) {
+		let mut data = self.data.write().await;
+		let max_stacks = stacks
+			.len()
+			.min(ITEM_BUFFER_LIMIT.saturating_sub(data.len()));
+		data.extend(stacks.drain(0..max_stacks));
+		if max_stacks > 0 {
+			self.changed(data.len());
+		}
+	}
+	/// 空き容量
+	pub async fn free(&self) -> usize {
+		ITEM_BUFFER_LIMIT.saturating_sub(self.len().await)
+	}
+	/// 取り出したスタックを搬入時刻と番号を変えずに先頭へ戻す 入りきらない分は`stacks`に残る
+	pub async fn restore_items(&self, stacks: &mut Vec<ItemStack>) {
+		let mut data = self.data.write().await;
+		let max_stacks = stacks
+			.len()
+			.min(ITEM_BUFFER_LIMIT.saturating_sub(data.len()));
+		data.splice(0..0, stacks.drain(0..max_stacks));
+		if max_stacks > 0 {
+			self.changed(data.len());
+		}
+	}
+	/// `deadline`(UNIXミリ秒)より前に搬入されたスタックを取り出す
+	pub async fn take_expired(&self, deadline: i64) -> Vec<ItemStack> {
+		let mut data = self.data.write().await;
+		let (expired, alive) = std::mem::take(&mut *data)
+			.into_iter()
+			.partition(|is: &ItemStack| is.meta.inserted_at < deadline);
+		*data = alive;
+		if !expired.is_empty() {
+			self.changed(data.len());
+		}
+		expired
 	}
 	pub async fn len(&self) -> usize {
 		self.data.read().await.len()
 	}
+	/// `StackMeta::id`で探す
+	pub async fn find(&self, id: u64) -> Option<ItemStack> {
+		let data = self.data.read().await;
+		data.iter().find(|is| is.meta.id == id).cloned()
+	}
+}
+/// 搬出順序
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemOrder {
+	#[default]
+	Fifo,
+	Lifo,
+	/// アイテムIDごとに1スタックずつ順番に
+	RoundRobin,
+	/// 先に一致したパターンほど優先 同じ優先度ではFIFO パターンは`*`のみ対応
+	Priority(Vec<String>),
+}
+impl ItemOrder {
+	/// 搬出するスタックの添字を搬出順に返す
+	fn select(&self, data: &[ItemStack], max_stacks: usize) -> Vec<usize> {
+		match self {
+			ItemOrder::Fifo => (0..max_stacks).collect(),
+			ItemOrder::Lifo => (data.len() - max_stacks..data.len()).rev().collect(),
+			ItemOrder::RoundRobin => {
+				let mut groups: Vec<(&str, std::collections::VecDeque<usize>)> = Vec::new();
+				for (i, is) in data.iter().enumerate() {
+					match groups.iter_mut().find(|(id, _)| *id == is.id) {
+						Some((_, group)) => group.push_back(i),
+						None => groups.push((&is.id, [i].into())),
+					}
+				}
+				let mut indices = Vec::with_capacity(max_stacks);
+				while indices.len() < max_stacks {
+					for (_, group) in groups.iter_mut() {
+						if indices.len() >= max_stacks {
+							break;
+						}
+						if let Some(i) = group.pop_front() {
+							indices.push(i);
+						}
+					}
+				}
+				indices
+			}
+			ItemOrder::Priority(patterns) => {
+				let mut indices = (0..data.len()).collect::<Vec<_>>();
+				indices.sort_by_key(|i| {
+					patterns
+						.iter()
+						.position(|p| match_pattern(p, &data[*i].id))
+						.unwrap_or(patterns.len())
+				});
+				indices.truncate(max_stacks);
+				indices
+			}
+		}
+	}
+}
+pub(crate) fn match_pattern(pattern: &str, id: &str) -> bool {
+	let mut parts = pattern.split('*');
+	let first = parts.next().unwrap_or_default();
+	let Some(mut rest) = id.strip_prefix(first) else {
+		return false;
+	};
+	let mut parts = parts.collect::<Vec<_>>();
+	let Some(last) = parts.pop() else {
+		//ワイルドカード無し
+		return rest.is_empty();
+	};
+	for part in parts {
+		match rest.find(part) {
+			Some(pos) => rest = &rest[pos + part.len()..],
+			None => return false,
+		}
+	}
+	rest.len() >= last.len() && rest.ends_with(last)
 }
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd)]
 pub struct ItemStack {
@@ -43,6 +200,7 @@ pub struct ItemStack {
 	pub(crate) count: i32,
 	pub(crate) id: String,
 	pub(crate) nbt: Option<NBT>,
+	pub(crate) meta: StackMeta,
 }
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd)]
 pub enum NBT {
@@ -50,19 +208,39 @@ pub enum NBT {
 	Extra(Option<GzipNBT>),
 }
 impl NBT {
+	/// 通常のパケットに収まらない大きさならgzipにする
+	pub async fn from_raw(raw: Vec<u8>) -> Result<Self, tokio::io::Error> {
+		if raw.len() <= i16::MAX as usize {
+			Ok(NBT::Raw(raw))
+		} else {
+			Ok(NBT::Extra(Some(GzipNBT::from_raw(&raw).await?)))
+		}
+	}
+	/// gzipは展開する
+	pub async fn to_raw(&self) -> Result<Option<Vec<u8>>, tokio::io::Error> {
+		match self {
+			NBT::Raw(raw) => Ok(Some(raw.clone())),
+			NBT::Extra(Some(gz)) => gz.to_raw().await.map(Some),
+			NBT::Extra(None) => Ok(None),
+		}
+	}
 	pub fn hint(&self) -> String {
 		match self {
-			NBT::Raw(raw) => to_hex_string(&raw),
-			NBT::Extra(Some(gz)) => {
-				use md5::Digest;
-				let mut hasher = md5::Md5::new();
-				hasher.update(gz.as_gzip());
-				let result = hasher.finalize();
-				to_hex_string(&result)
-			}
+			NBT::Raw(raw) => to_hex_string(raw),
+			NBT::Extra(Some(gz)) => to_hex_string(gz.hash()),
 			_ => "".into(),
 		}
 	}
+	/// gzipは展開して解析する
+	pub async fn decode(&self) -> Result<Option<Tag>, tokio::io::Error> {
+		match self {
+			NBT::Raw(raw) => Tag::decode(raw).map(Some),
+			_ => match self.to_raw().await? {
+				Some(raw) => Tag::decode(&raw).map(Some),
+				None => Ok(None),
+			},
+		}
+	}
 }
 impl ItemStack {
 	pub async fn read<R: AsyncRead + std::marker::Unpin>(
@@ -86,17 +264,62 @@ impl ItemStack {
 			count,
 			id,
 			nbt,
+			meta: StackMeta::default(),
 		})
 	}
 	pub async fn read_extra<R: AsyncRead + std::marker::Unpin>(
 		&mut self,
 		r: &mut R,
+		store: &NbtStore,
 	) -> Result<(), tokio::io::Error> {
 		if let Some(NBT::Extra(nbt)) = &mut self.nbt {
 			let len = r.read_i32().await?;
-			let mut data = vec![0u8; len as usize];
-			r.read_exact(&mut data).await?;
-			*nbt = Some(GzipNBT::from_gzip(data));
+			if len == EXTRA_HASH_FLAG {
+				//サーバーが既に持っているNBTはハッシュのみ 未知ならNoneのまま
+				let mut hash = NbtHash::default();
+				r.read_exact(&mut hash).await?;
+				*nbt = store.get(&hash);
+			} else {
+				let len = len.try_into().map_err(tokio::io::Error::other)?;
+				let mut data = vec![0u8; len];
+				r.read_exact(&mut data).await?;
+				*nbt = Some(store.intern(GzipNBT::from_gzip(data))?);
+			}
+		}
+		Ok(())
+	}
+	/// gzipのNBTを共有ストアの物に置き換える
+	pub fn share_nbt(&mut self, store: &NbtStore) -> Result<(), tokio::io::Error> {
+		if let Some(NBT::Extra(Some(gz))) = &mut self.nbt {
+			*gz = store.intern(gz.clone())?;
+		}
+		Ok(())
+	}
+	/// `read_extra`でNBTが揃わなかった
+	pub fn is_missing_extra(&self) -> bool {
+		matches!(self.nbt, Some(NBT::Extra(None)))
+	}
+	/// セーブデータ用 NBTはハッシュのみ書き込む
+	pub async fn write_extra_hash<W: AsyncWrite + std::marker::Unpin>(
+		&self,
+		w: &mut W,
+	) -> Result<(), tokio::io::Error> {
+		if let Some(NBT::Extra(Some(gz))) = self.nbt.as_ref() {
+			w.write_all(gz.hash()).await?;
+		}
+		Ok(())
+	}
+	/// セーブデータ用 NBTは先に読み込んだ共有ストアから引く
+	pub async fn read_extra_hash<R: AsyncRead + std::marker::Unpin>(
+		&mut self,
+		r: &mut R,
+		store: &NbtStore,
+	) -> Result<(), tokio::io::Error> {
+		if let Some(NBT::Extra(nbt)) = &mut self.nbt {
+			let mut hash = NbtHash::default();
+			r.read_exact(&mut hash).await?;
+			let gz = store.get(&hash);
+			*nbt = Some(gz.ok_or_else(|| tokio::io::Error::other("Unknown NBT Hash"))?);
 		}
 		Ok(())
 	}
@@ -112,12 +335,9 @@ impl ItemStack {
 				w.write_i16(0).await?;
 			}
 			Some(NBT::Raw(nbt)) => {
-				let len = nbt
-					.len()
-					.try_into()
-					.map_err(|e| tokio::io::Error::other(e))?;
+				let len = nbt.len().try_into().map_err(tokio::io::Error::other)?;
 				w.write_i16(len).await?;
-				w.write_all(&nbt).await?;
+				w.write_all(nbt).await?;
 			}
 			Some(NBT::Extra(_)) => {
 				w.write_i16(-1).await?; //遅延書き込みフラグ
@@ -129,23 +349,26 @@ impl ItemStack {
 		&self,
 		w: &mut W,
 	) -> Result<(), tokio::io::Error> {
-		match self.nbt.as_ref() {
-			Some(NBT::Extra(Some(gz))) => {
-				w.write_i32(gz.as_gzip().len() as i32).await?;
-				w.write_all(gz.as_gzip()).await?;
-			}
-			_ => {}
+		if let Some(NBT::Extra(Some(gz))) = self.nbt.as_ref() {
+			w.write_i32(gz.as_gzip().len() as i32).await?;
+			w.write_all(gz.as_gzip()).await?;
 		}
 		Ok(())
 	}
 }
 
+impl GlobalObject {
+	/// 無ければ作る
+	pub(crate) fn items(&self, freq: &Frequency) -> Arc<Items> {
+		self.item_buffers.get_or_insert_with(freq, || {
+			Arc::new(Items::new().with_notifier(self.notifier(freq)))
+		})
+	}
+}
 impl ClientSession {
 	pub(crate) async fn item_recv(&mut self) -> Result<(), tokio::io::Error> {
 		let data_size = self.reader.read_i32().await?;
-		let data_size = data_size
-			.try_into()
-			.map_err(|e| tokio::io::Error::other(e))?;
+		let data_size = data_size.try_into().map_err(tokio::io::Error::other)?;
 		let mut raw_data = vec![0u8; data_size];
 		self.reader.read_exact(&mut raw_data).await?;
 		let mut raw_data = std::io::Cursor::new(&raw_data);
@@ -157,28 +380,36 @@ impl ClientSession {
 			insert_items.push(is);
 		}
 		for is in insert_items.iter_mut() {
-			is.read_extra(&mut self.reader).await?;
+			is.read_extra(&mut self.reader, &self.go.nbt_store).await?;
 		}
-		let cache_hit = { self.go.item_buffers.read().await.get(self.freq()).cloned() };
-		let freq_buffer = if let Some(cache_hit) = cache_hit {
-			cache_hit
-		} else {
-			let mut item_buffers = self.go.item_buffers.write().await;
-			let freq_buffer = item_buffers.get(self.freq()).cloned();
-			match freq_buffer {
-				Some(v) => v,
-				None => {
-					let v = Arc::new(Items::new());
-					item_buffers.insert(self.freq().clone(), v.clone());
-					v
-				}
+		//NBTが揃わなかったスタックは再送してもらう
+		let mut rejects = Vec::new();
+		let mut accepts = Vec::new();
+		let mut accept_items = Vec::new();
+		for (i, is) in insert_items.into_iter().enumerate() {
+			if is.is_missing_extra() {
+				rejects.push(i as i32);
+			} else {
+				accepts.push(i as i32);
+				accept_items.push(is);
 			}
-		};
-		freq_buffer.insert_items(&mut insert_items).await;
-		let reject_start = item_count - insert_items.len() as i32;
+		}
+		let freq_buffer = self.go.items(self.freq());
+		let offered = accept_items
+			.iter()
+			.map(|is| (is.id.clone(), is.count))
+			.collect::<Vec<_>>();
+		freq_buffer.insert_items(&mut accept_items).await;
+		for (id, count) in &offered[..offered.len() - accept_items.len()] {
+			self.audit(Kind::Item, Direction::Insert, Some(id), *count as i64)
+				.await;
+		}
+		rejects.extend_from_slice(&accepts[accepts.len() - accept_items.len()..]);
+		rejects.sort();
+		self.go.metrics.rejected_items(rejects.len());
 		let mut write_buffer = async_compression::tokio::write::GzipEncoder::new(Vec::new());
-		write_buffer.write_i32(item_count - reject_start).await?;
-		for i in reject_start..item_count {
+		write_buffer.write_i32(rejects.len() as i32).await?;
+		for i in rejects {
 			write_buffer.write_i32(i).await?;
 		}
 		write_buffer.shutdown().await?;
@@ -189,14 +420,34 @@ impl ClientSession {
 	}
 	pub(crate) async fn item_send(&mut self) -> Result<(), tokio::io::Error> {
 		let max_stacks = self.reader.read_i32().await?;
-		let freq_buffer = self.go.item_buffers.read().await.get(self.freq()).cloned();
+		let freq_buffer = self.go.item_buffers.get(self.freq());
 		let freq_buffer = if let Some(b) = freq_buffer {
 			b
 		} else {
 			self.writer.write_i32(0).await?;
 			return Ok(());
 		};
-		let items = freq_buffer.take_items(max_stacks).await;
+		let order = self
+			.go
+			.config
+			.read()
+			.await
+			.frequency(self.freq())
+			.item_order;
+		let available = freq_buffer.len().await as i64;
+		let share = self.fair_share(Resource::Item, available).await;
+		let max_stacks = max_stacks.min(share.min(i32::MAX as i64) as i32);
+		let items = freq_buffer.take_items(max_stacks, &order).await;
+		self.fair_took(&Resource::Item, items.len() as i64).await;
+		for item in &items {
+			self.audit(
+				Kind::Item,
+				Direction::Take,
+				Some(&item.id),
+				item.count as i64,
+			)
+			.await;
+		}
 		let mut write_buffer = async_compression::tokio::write::GzipEncoder::new(Vec::new());
 		write_buffer.write_i32(items.len() as i32).await?;
 		for item in &items {
@@ -211,45 +462,71 @@ impl ClientSession {
 		}
 		Ok(())
 	}
+	/// 搬入出せずにスタック数と空き容量を返す
+	pub(crate) async fn item_query(&mut self) -> Result<(), tokio::io::Error> {
+		let freq_buffer = self.go.item_buffers.get(self.freq());
+		let len = match freq_buffer {
+			Some(freq_buffer) => freq_buffer.len().await,
+			None => 0,
+		};
+		self.writer.write_i32(len as i32).await?;
+		self.writer
+			.write_i32(ITEM_BUFFER_LIMIT.saturating_sub(len) as i32)
+			.await?;
+		Ok(())
+	}
 }
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd)]
 pub struct GzipNBT {
-	data: Vec<u8>,
+	hash: NbtHash,
+	data: Arc<[u8]>,
 }
 impl GzipNBT {
+	/// 共有はしない 共有するには`NbtStore::intern`に通す
 	pub fn from_gzip(data: Vec<u8>) -> Self {
-		Self { data }
+		Self {
+			hash: nbt_store::hash(&data),
+			data: data.into(),
+		}
+	}
+	pub(crate) fn from_shared(hash: NbtHash, data: Arc<[u8]>) -> Self {
+		Self { hash, data }
+	}
+	pub(crate) fn shared_data(&self) -> Arc<[u8]> {
+		self.data.clone()
 	}
 	pub fn as_gzip(&self) -> &[u8] {
 		&self.data
 	}
+	pub fn hash(&self) -> &NbtHash {
+		&self.hash
+	}
+	pub async fn from_raw(raw: &[u8]) -> Result<Self, tokio::io::Error> {
+		let mut write_buffer = async_compression::tokio::write::GzipEncoder::new(Vec::new());
+		write_buffer.write_all(raw).await?;
+		write_buffer.shutdown().await?;
+		let compressed_bytes = write_buffer.into_inner();
+		Ok(Self::from_gzip(compressed_bytes))
+	}
+	pub async fn to_raw(&self) -> Result<Vec<u8>, tokio::io::Error> {
+		let mut gz_data = std::io::Cursor::new(self.as_gzip());
+		let reader = async_compression::tokio::bufread::GzipDecoder::new(&mut gz_data);
+		let mut raw = Vec::new();
+		reader.take(MAX_NBT_BYTES + 1).read_to_end(&mut raw).await?;
+		if raw.len() as u64 > MAX_NBT_BYTES {
+			return Err(tokio::io::Error::other("NBT too large"));
+		}
+		Ok(raw)
+	}
 }
 
 #[cfg(test)]
 mod tests {
-	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio::io::AsyncWriteExt;
 
-	use super::{GzipNBT, ItemStack, Items, ITEM_BUFFER_LIMIT, NBT};
+	use super::{match_pattern, GzipNBT, ItemOrder, ItemStack, Items, ITEM_BUFFER_LIMIT, NBT};
+	use crate::nbt_store::NbtStore;
 
-	impl GzipNBT {
-		pub async fn from_raw(raw: &[u8]) -> Result<Self, tokio::io::Error> {
-			let mut write_buffer = async_compression::tokio::write::GzipEncoder::new(Vec::new());
-			write_buffer.write(raw).await?;
-			write_buffer.shutdown().await?;
-			let compressed_bytes = write_buffer.into_inner();
-			Ok(Self {
-				data: compressed_bytes,
-			})
-		}
-		#[allow(dead_code)]
-		pub async fn to_raw(&self) -> Result<Vec<u8>, tokio::io::Error> {
-			let mut gz_data = std::io::Cursor::new(&self.data);
-			let mut reader = async_compression::tokio::bufread::GzipDecoder::new(&mut gz_data);
-			let mut raw = Vec::new();
-			reader.read_to_end(&mut raw).await?;
-			Ok(raw)
-		}
-	}
 	impl Items {
 		pub async fn to_vec(&self) -> Vec<ItemStack> {
 			let mut items = Vec::new();
@@ -261,11 +538,15 @@ mod tests {
 	}
 	impl ItemStack {
 		pub fn dummy() -> Self {
+			Self::dummy_with_id("minecraft:stone")
+		}
+		pub fn dummy_with_id(id: &str) -> Self {
 			Self {
-				id: "minecraft:stone".into(),
+				id: id.into(),
 				damage: 0,
 				count: 64,
 				nbt: Some(NBT::Raw(vec![0, 1, 2, 3])),
+				meta: Default::default(),
 			}
 		}
 		pub async fn heavy_dummy() -> Self {
@@ -280,6 +561,7 @@ mod tests {
 				damage: 0,
 				count: 64,
 				nbt: Some(NBT::Extra(Some(GzipNBT::from_raw(&nbt).await.unwrap()))),
+				meta: Default::default(),
 			}
 		}
 	}
@@ -305,17 +587,79 @@ mod tests {
 				items.insert_items(&mut add_stacks).await;
 				assert_eq!(add_stacks.len(), 5);
 				assert_eq!(items.data.read().await.len(), ITEM_BUFFER_LIMIT);
-				let take_items = items.take_items(5).await;
+				let take_items = items.take_items(5, &ItemOrder::Fifo).await;
 				assert_eq!(take_items.len(), 5);
 				assert_eq!(items.data.read().await.len(), ITEM_BUFFER_LIMIT - 5);
 				let old = items.data.read().await.clone();
-				let take_items = items.take_items(ITEM_BUFFER_LIMIT as i32).await;
+				let take_items = items
+					.take_items(ITEM_BUFFER_LIMIT as i32, &ItemOrder::Fifo)
+					.await;
 				assert_eq!(take_items.len(), ITEM_BUFFER_LIMIT - 5);
 				assert_eq!(items.data.read().await.len(), 0);
 				assert_eq!(old, take_items);
 			});
 	}
 	#[test]
+	fn item_order() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let ids = [
+					"minecraft:cobblestone",
+					"minecraft:cobblestone",
+					"minecraft:diamond",
+					"minecraft:cobblestone",
+					"mod:nether_star",
+				];
+				let take = |order: ItemOrder, max_stacks: i32| async move {
+					let items = Items::new();
+					let mut add_stacks =
+						ids.iter().map(|id| ItemStack::dummy_with_id(id)).collect();
+					items.insert_items(&mut add_stacks).await;
+					let taken = items.take_items(max_stacks, &order).await;
+					let rest = items.to_vec().await;
+					assert_eq!(taken.len() + rest.len(), ids.len());
+					taken.into_iter().map(|is| is.id).collect::<Vec<_>>()
+				};
+				assert_eq!(
+					take(ItemOrder::Lifo, 2).await,
+					["mod:nether_star", "minecraft:cobblestone"]
+				);
+				assert_eq!(
+					take(ItemOrder::RoundRobin, 4).await,
+					[
+						"minecraft:cobblestone",
+						"minecraft:diamond",
+						"mod:nether_star",
+						"minecraft:cobblestone"
+					]
+				);
+				assert_eq!(
+					take(
+						ItemOrder::Priority(vec!["*:nether_star".into(), "minecraft:dia*".into()]),
+						3
+					)
+					.await,
+					[
+						"mod:nether_star",
+						"minecraft:diamond",
+						"minecraft:cobblestone"
+					]
+				);
+			});
+	}
+	#[test]
+	fn pattern() {
+		assert!(match_pattern("minecraft:stone", "minecraft:stone"));
+		assert!(!match_pattern("minecraft:stone", "minecraft:stone_slab"));
+		assert!(match_pattern("minecraft:*", "minecraft:stone"));
+		assert!(match_pattern("*:stone*", "mod:stone_slab"));
+		assert!(match_pattern("*", "mod:stone_slab"));
+		assert!(!match_pattern("*:ab*ba", "mod:aba"));
+	}
+	#[test]
 	fn read_write_item() {
 		let src = ItemStack::dummy();
 		tokio::runtime::Builder::new_current_thread()
@@ -328,8 +672,44 @@ mod tests {
 				src.write_extra(&mut v).await.unwrap();
 				let r = &mut std::io::Cursor::new(&v);
 				let mut dst = ItemStack::read(r).await.unwrap();
-				dst.read_extra(r).await.unwrap();
+				dst.read_extra(r, &NbtStore::default()).await.unwrap();
+				assert_eq!(src, dst);
+			});
+	}
+	#[test]
+	fn read_extra_hash() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let store = NbtStore::default();
+				let mut src = ItemStack::heavy_dummy().await;
+				src.share_nbt(&store).unwrap();
+				let Some(NBT::Extra(Some(gz))) = &src.nbt else {
+					unreachable!()
+				};
+				let mut v = Vec::new();
+				src.write(&mut v).await.unwrap();
+				v.write_i32(super::EXTRA_HASH_FLAG).await.unwrap();
+				v.write_all(gz.hash()).await.unwrap();
+				let r = &mut std::io::Cursor::new(&v);
+				let mut dst = ItemStack::read(r).await.unwrap();
+				dst.read_extra(r, &store).await.unwrap();
 				assert_eq!(src, dst);
+				let Some(NBT::Extra(Some(dst_gz))) = &dst.nbt else {
+					unreachable!()
+				};
+				assert!(std::ptr::eq(gz.as_gzip(), dst_gz.as_gzip()));
+				//未知のハッシュは揃わない
+				let mut v = Vec::new();
+				src.write(&mut v).await.unwrap();
+				v.write_i32(super::EXTRA_HASH_FLAG).await.unwrap();
+				v.write_all(&[0xAB; 16]).await.unwrap();
+				let r = &mut std::io::Cursor::new(&v);
+				let mut dst = ItemStack::read(r).await.unwrap();
+				dst.read_extra(r, &store).await.unwrap();
+				assert!(dst.is_missing_extra());
 			});
 	}
 	#[test]
@@ -345,8 +725,24 @@ mod tests {
 				src.write_extra(&mut v).await.unwrap();
 				let r = &mut std::io::Cursor::new(&v);
 				let mut dst = ItemStack::read(r).await.unwrap();
-				dst.read_extra(r).await.unwrap();
+				dst.read_extra(r, &NbtStore::default()).await.unwrap();
 				assert_eq!(src, dst);
 			});
 	}
+	#[test]
+	fn nbt_size_limit() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let limit = super::MAX_NBT_BYTES as usize;
+				let gz = GzipNBT::from_raw(&vec![0; limit]).await.unwrap();
+				assert_eq!(gz.to_raw().await.unwrap().len(), limit);
+				//小さなgzipでも展開後が上限を超えたら止める
+				let bomb = GzipNBT::from_raw(&vec![0; limit + 1]).await.unwrap();
+				assert!(bomb.as_gzip().len() < limit / 100);
+				assert!(bomb.to_raw().await.is_err());
+			});
+	}
 }
@@ -0,0 +1,83 @@
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use tracing_subscriber::{
+	fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+use crate::config::Config;
+
+/// `log_level`も環境変数`RUST_LOG`も無い場合
+const LOG_LEVEL: &str = "info";
+
+/// ログの出力形式 起動時のみ反映
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+	#[default]
+	Text,
+	/// 1行1イベントのJSON
+	Json,
+}
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// `info`や`warn,FedStorageServer_rs=debug`の形式
+fn filter(directives: &str) -> Result<EnvFilter, tokio::io::Error> {
+	EnvFilter::try_new(directives).map_err(tokio::io::Error::other)
+}
+fn configured_level(config: &Config) -> String {
+	config
+		.log_level
+		.clone()
+		.or_else(|| std::env::var(EnvFilter::DEFAULT_ENV).ok())
+		.unwrap_or_else(|| LOG_LEVEL.into())
+}
+pub(crate) fn init(config: &Config) {
+	let level = configured_level(config);
+	let (initial, invalid) = match filter(&level) {
+		Ok(f) => (f, None),
+		Err(e) => (EnvFilter::new(LOG_LEVEL), Some(e)),
+	};
+	let (filter, handle) = reload::Layer::new(initial);
+	let json = config.log_format == LogFormat::Json;
+	tracing_subscriber::registry()
+		.with(filter)
+		.with((!json).then(fmt::layer))
+		.with(json.then(|| fmt::layer().json().with_current_span(true)))
+		.init();
+	let _ = FILTER.set(handle);
+	if let Some(e) = invalid {
+		tracing::warn!(level, error = %e, "invalid log level");
+	}
+}
+/// 実行中にログレベルを変える 初期化前は何もしない
+pub(crate) fn set_level(directives: &str) -> Result<(), tokio::io::Error> {
+	let filter = filter(directives)?;
+	if let Some(handle) = FILTER.get() {
+		handle.reload(filter).map_err(tokio::io::Error::other)?;
+	}
+	Ok(())
+}
+/// 設定ファイルの再読み込み時
+pub(crate) fn apply(config: &Config) -> Result<(), tokio::io::Error> {
+	set_level(&configured_level(config))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::LogFormat;
+	use crate::config::Config;
+
+	#[test]
+	fn log_config() {
+		let config: Config = serde_json::from_str(
+			r#"{ "log_level": "warn,FedStorageServer_rs=debug", "log_format": "json" }"#,
+		)
+		.unwrap();
+		assert_eq!(config.log_format, LogFormat::Json);
+		assert!(super::apply(&config).is_ok());
+		assert!(super::set_level("info,=[").is_err());
+		assert_eq!(Config::default().log_format, LogFormat::Text);
+	}
+}
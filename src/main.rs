@@ -1,77 +1,163 @@
+#![allow(clippy::upper_case_acronyms)]
+
 use std::{collections::HashMap, sync::Arc};
 
 use client::{ClientMeta, ClientSession};
+use config::Config;
+use energy::Energy;
 use fluid::Fluids;
+use frequency_map::FrequencyMap;
 use item::Items;
 use tokio::{
 	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 	net::TcpListener,
-	sync::{Mutex, RwLock},
+	sync::{watch, Mutex, RwLock},
 };
+use tracing::Instrument;
 
+mod audit;
 mod cli;
 mod client;
+mod config;
 mod energy;
+mod events;
+mod fair;
 mod fluid;
+mod frequency_map;
+mod gc;
+mod health;
+mod history;
 mod http;
 mod item;
+mod logging;
+mod meta;
+mod metrics;
+mod nbt;
+mod nbt_store;
+mod search;
+mod summary;
 
 fn main() {
 	let rt = tokio::runtime::Builder::new_multi_thread()
 		.enable_all()
 		.build()
 		.expect("async runtime");
-	let go = Arc::new(GlobalObject::new());
+	let config = rt.block_on(Config::load_file(config::CONFIG_FILE));
+	let config = match config {
+		Ok(config) => config,
+		Err(e) => {
+			//ログの設定も読めていないので標準エラーに出す
+			eprintln!("config error: {}", e);
+			std::process::exit(1);
+		}
+	};
+	logging::init(&config);
+	let go = Arc::new(GlobalObject::new(config));
 	let cloned = go.clone();
 	rt.spawn(async move {
+		//読み込みが終わってから受け付ける
+		cli::autoload(&go).await;
 		let bind = TcpListener::bind("0.0.0.0:3030").await;
 		let listener = bind.expect("bind error");
+		go.health.listener_bound();
+		tracing::info!(addr = "0.0.0.0:3030", "tcp listening");
 		loop {
 			tcp_loop(&listener, go.clone()).await;
 		}
 	});
 	rt.spawn(cli::cli(cloned.clone()));
-	rt.block_on(http::server(cloned))
+	rt.spawn(gc::gc_loop(cloned.clone()));
+	rt.spawn(history::history_loop(cloned.clone()));
+	let audit = rt.spawn(audit::audit_loop(cloned.clone()));
+	rt.spawn(cli::autosave_loop(cloned.clone()));
+	rt.block_on(http::server(cloned));
+	//監査ログの書き残しを待つ
+	let flush = tokio::time::timeout(
+		std::time::Duration::from_secs(audit::AUDIT_FLUSH_SECS),
+		audit,
+	);
+	if rt.block_on(flush).is_err() {
+		tracing::warn!("audit log flush timed out");
+	}
+	//標準入力の読み込みを待たずに終わる
+	rt.shutdown_background();
 }
 async fn tcp_loop(listener: &TcpListener, go: Arc<GlobalObject>) {
 	match listener.accept().await {
 		Ok((soc, addr)) => {
-			println!("connect");
 			let client = ClientSession::new(soc, addr, go.clone());
-			tokio::runtime::Handle::current().spawn(async move {
-				let sid = client.meta.lock().await.id;
-				if let Err(e) = client.session().await {
-					eprintln!("{:?}", e);
+			let span = client.span.clone();
+			let session = async move {
+				tracing::info!("connect");
+				match client.session().await {
+					Ok(()) => tracing::info!("disconnect"),
+					Err(e) if e.kind() == tokio::io::ErrorKind::UnexpectedEof => {
+						tracing::info!("disconnect")
+					}
+					Err(e) => tracing::warn!(error = %e, "session error"),
 				}
-				go.clients.write().await.remove(&sid);
-			});
+			};
+			tokio::runtime::Handle::current().spawn(session.instrument(span));
 		}
 		Err(e) => {
-			println!("client tcp error {:?}", e);
+			tracing::error!(error = %e, "client tcp error");
 		}
 	}
 }
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd)]
 pub struct Frequency(pub String);
 struct GlobalObject {
-	item_buffers: RwLock<HashMap<Frequency, Arc<Items>>>,
-	fluid_buffers: RwLock<HashMap<Frequency, Arc<Fluids>>>,
-	energy_buffers: RwLock<HashMap<Frequency, i64>>,
+	item_buffers: FrequencyMap<Arc<Items>>,
+	fluid_buffers: FrequencyMap<Arc<Fluids>>,
+	energy_buffers: FrequencyMap<Arc<Energy>>,
 	clients: RwLock<HashMap<uuid::Uuid, Arc<Mutex<ClientMeta>>>>,
+	config: RwLock<Config>,
+	/// 真になるとHTTPサーバーが終了し、プロセスが終わる
+	shutdown: watch::Sender<bool>,
+	events: tokio::sync::broadcast::Sender<events::Event>,
+	metrics: metrics::Metrics,
+	history: history::History,
+	audit: audit::AuditLog,
+	health: health::Health,
+	fair_shares: fair::FairShares,
+	nbt_store: nbt_store::NbtStore,
+	nbt_display: nbt_store::DisplayCache,
+	/// CLI・管理API・自動保存の保存が重ならないようにする
+	save_lock: Mutex<()>,
 }
 impl GlobalObject {
-	fn new() -> Self {
+	fn new(config: Config) -> Self {
 		Self {
-			item_buffers: RwLock::new(HashMap::new()),
-			fluid_buffers: RwLock::new(HashMap::new()),
-			energy_buffers: RwLock::new(HashMap::new()),
+			item_buffers: FrequencyMap::new(),
+			fluid_buffers: FrequencyMap::new(),
+			energy_buffers: FrequencyMap::new(),
 			clients: RwLock::new(HashMap::new()),
+			config: RwLock::new(config),
+			shutdown: watch::Sender::new(false),
+			events: events::channel(),
+			metrics: metrics::Metrics::default(),
+			history: history::History::default(),
+			audit: audit::AuditLog::default(),
+			health: health::Health::default(),
+			fair_shares: fair::FairShares::default(),
+			nbt_store: nbt_store::NbtStore::default(),
+			nbt_display: nbt_store::DisplayCache::default(),
+			save_lock: Mutex::new(()),
 		}
 	}
 }
 pub fn to_hex_string(v: &[u8]) -> String {
 	v.iter().map(|n| format!("{:02X}", n)).collect::<String>()
 }
+pub fn from_hex_string(s: &str) -> Option<Vec<u8>> {
+	if !s.len().is_multiple_of(2) {
+		return None;
+	}
+	(0..s.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+		.collect()
+}
 pub async fn read_string<R: AsyncRead + std::marker::Unpin>(
 	reader: &mut R,
 ) -> Result<String, tokio::io::Error> {
@@ -79,14 +165,14 @@ pub async fn read_string<R: AsyncRead + std::marker::Unpin>(
 	let mut v = vec![0u8; len.into()];
 	reader.read_exact(&mut v).await?;
 	let s = String::from_utf8(v);
-	s.map_err(|e| tokio::io::Error::other(e))
+	s.map_err(tokio::io::Error::other)
 }
 pub async fn write_string<W: AsyncWrite + std::marker::Unpin>(
 	writer: &mut W,
 	s: impl AsRef<str>,
 ) -> Result<(), tokio::io::Error> {
 	let s = s.as_ref().as_bytes();
-	let len = s.len().try_into().map_err(|e| tokio::io::Error::other(e))?;
+	let len = s.len().try_into().map_err(tokio::io::Error::other)?;
 	writer.write_u16(len).await?;
 	writer.write_all(s).await?;
 	Ok(())
@@ -94,48 +180,35 @@ pub async fn write_string<W: AsyncWrite + std::marker::Unpin>(
 
 #[cfg(test)]
 mod tests {
-	use std::{collections::HashMap, sync::Arc, u32};
-
-	use tokio::sync::RwLock;
-
 	use crate::{
-		fluid::{FluidStack, Fluids},
-		item::{ItemStack, Items},
-		read_string, write_string, GlobalObject,
+		config::Config, fluid::FluidStack, from_hex_string, item::ItemStack, read_string,
+		to_hex_string, write_string, Frequency, GlobalObject,
 	};
 	impl GlobalObject {
 		pub async fn dummy() -> Self {
-			let mut item_buffers = HashMap::new();
-			let items = Items::new();
-			items.insert_items(&mut [ItemStack::dummy()].to_vec()).await;
-			item_buffers.insert(crate::Frequency("RED, RED, RED".into()), Arc::new(items));
-			let items = Items::new();
-			items
+			let go = Self::new(Config::default());
+			go.items(&Frequency("RED, RED, RED".into()))
+				.insert_items(&mut [ItemStack::dummy()].to_vec())
+				.await;
+			go.items(&Frequency("WHITE, BLUE, WHITE".into()))
 				.insert_items(&mut [ItemStack::heavy_dummy().await].to_vec())
 				.await;
-			item_buffers.insert(
-				crate::Frequency("WHITE, BLUE, WHITE".into()),
-				Arc::new(items),
-			);
-			let mut fluid_buffers = HashMap::new();
-			let fluids = Fluids::new();
-			fluids.insert_fluid(FluidStack::dummy()).await;
-			fluid_buffers.insert(crate::Frequency("RED, RED, RED".into()), Arc::new(fluids));
-			let mut energy_buffers = HashMap::new();
-			energy_buffers.insert(
-				crate::Frequency("WHITE, WHITE, WHITE".into()),
-				u32::MAX as i64 + 500,
-			);
-			let mut go = Self {
-				item_buffers: RwLock::new(item_buffers),
-				fluid_buffers: RwLock::new(fluid_buffers),
-				energy_buffers: RwLock::new(energy_buffers),
-				clients: RwLock::new(HashMap::new()),
-			};
+			go.fluids(&Frequency("RED, RED, RED".into()))
+				.insert_fluid(FluidStack::dummy())
+				.await;
+			go.energy(&Frequency("WHITE, WHITE, WHITE".into()))
+				.merge(u32::MAX as i64 + 500);
 			go
 		}
 	}
 	#[test]
+	fn hex_string() {
+		let v = vec![0x00, 0x7F, 0xAB, 0xFF];
+		assert_eq!(from_hex_string(&to_hex_string(&v)), Some(v));
+		assert_eq!(from_hex_string("abc"), None);
+		assert_eq!(from_hex_string("zz"), None);
+	}
+	#[test]
 	fn read_write_string() {
 		tokio::runtime::Builder::new_current_thread()
 			.enable_all()
@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_STACK_ID: AtomicU64 = AtomicU64::new(1);
+
+/// スタックの付随情報 スタックの同一性の比較には含めない
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StackMeta {
+	/// 搬入時刻(UNIXミリ秒)
+	pub(crate) inserted_at: i64,
+	/// 搬入ごとに振る番号 周波数内で一意で、アイテムはセーブデータにも保存する
+	pub(crate) id: u64,
+}
+impl StackMeta {
+	pub fn now() -> Self {
+		Self {
+			inserted_at: chrono::Utc::now().timestamp_millis(),
+			id: NEXT_STACK_ID.fetch_add(1, Ordering::Relaxed),
+		}
+	}
+	/// セーブデータから読み込んだ番号を使う 以降に振る番号はこれより大きくする
+	pub fn restore(inserted_at: i64, id: u64) -> Self {
+		NEXT_STACK_ID.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+		Self { inserted_at, id }
+	}
+	/// 搬入時刻はそのままで番号を振り直す
+	pub fn renew(self) -> Self {
+		Self {
+			id: NEXT_STACK_ID.fetch_add(1, Ordering::Relaxed),
+			..self
+		}
+	}
+}
+impl PartialEq for StackMeta {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
+}
+impl Eq for StackMeta {}
+impl PartialOrd for StackMeta {
+	fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+		Some(std::cmp::Ordering::Equal)
+	}
+}
+impl std::hash::Hash for StackMeta {
+	fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
@@ -0,0 +1,357 @@
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{energy::energy_capacity, GlobalObject};
+
+/// コマンド番号-1から順に数える
+const COMMAND_SLOTS: usize = 16;
+
+/// Prometheus向けの累計値
+#[derive(Debug, Default)]
+pub struct Metrics {
+	commands: [AtomicU64; COMMAND_SLOTS],
+	pub(crate) bytes_in: Arc<AtomicU64>,
+	pub(crate) bytes_out: Arc<AtomicU64>,
+	rejected_item_stacks: AtomicU64,
+	rejected_energy: AtomicU64,
+}
+impl Metrics {
+	pub fn command(&self, command: i8) {
+		if let Some(counter) = usize::try_from(command as i32 + 1)
+			.ok()
+			.and_then(|i| self.commands.get(i))
+		{
+			counter.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+	pub fn rejected_items(&self, stacks: usize) {
+		self.rejected_item_stacks
+			.fetch_add(stacks as u64, Ordering::Relaxed);
+	}
+	pub fn rejected_energy(&self, amount: i64) {
+		self.rejected_energy
+			.fetch_add(amount.max(0) as u64, Ordering::Relaxed);
+	}
+	fn commands(&self) -> impl Iterator<Item = (i8, u64)> + '_ {
+		self.commands
+			.iter()
+			.enumerate()
+			.map(|(i, counter)| (i as i8 - 1, counter.load(Ordering::Relaxed)))
+	}
+}
+/// 読み書きしたバイト数を数える
+pub struct Counted<T> {
+	inner: T,
+	counters: Vec<Arc<AtomicU64>>,
+}
+impl<T> Counted<T> {
+	pub fn new(inner: T, counter: Arc<AtomicU64>) -> Self {
+		Self {
+			inner,
+			counters: vec![counter],
+		}
+	}
+	/// クライアントごとの集計など、同じ量を別の値にも足す
+	pub fn with_counter(mut self, counter: &Arc<AtomicU64>) -> Self {
+		self.counters.push(counter.clone());
+		self
+	}
+	fn count(&self, n: usize) {
+		for counter in &self.counters {
+			counter.fetch_add(n as u64, Ordering::Relaxed);
+		}
+	}
+}
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<std::io::Result<()>> {
+		let before = buf.filled().len();
+		let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+		self.count(buf.filled().len() - before);
+		poll
+	}
+}
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(written)) = &poll {
+			self.count(*written);
+		}
+		poll
+	}
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+/// テキスト形式で書き出す
+struct Exposition(String);
+impl Exposition {
+	fn header(&mut self, name: &str, kind: &str, help: &str) {
+		let _ = writeln!(self.0, "# HELP {} {}", name, help);
+		let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+	}
+	fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+		self.0 += name;
+		if !labels.is_empty() {
+			let labels = labels
+				.iter()
+				.map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+				.collect::<Vec<_>>();
+			let _ = write!(self.0, "{{{}}}", labels.join(","));
+		}
+		let _ = writeln!(self.0, " {}", value);
+	}
+}
+fn escape(v: &str) -> String {
+	v.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+impl GlobalObject {
+	pub(crate) async fn render_metrics(&self) -> String {
+		let mut out = Exposition(String::new());
+		out.header(
+			"fedstorage_item_stacks",
+			"gauge",
+			"Stacks stored per item frequency.",
+		);
+		for (freq, items) in self.item_buffers.snapshot() {
+			let len = items.len().await;
+			out.sample("fedstorage_item_stacks", &[("frequency", &freq.0)], len);
+		}
+		out.header(
+			"fedstorage_fluid_amount",
+			"gauge",
+			"Fluid stored per frequency and fluid name.",
+		);
+		for (freq, fluids) in self.fluid_buffers.snapshot() {
+			let mut amounts = BTreeMap::<String, i64>::new();
+			for fs in fluids.snapshot().await {
+				*amounts.entry(fs.name).or_default() += fs.count;
+			}
+			for (name, count) in amounts {
+				let labels = [("frequency", freq.0.as_str()), ("fluid", &name)];
+				out.sample("fedstorage_fluid_amount", &labels, count);
+			}
+		}
+		let config = self.config.read().await.clone();
+		let energy_buffers = self.energy_buffers.snapshot();
+		out.header("fedstorage_energy", "gauge", "Energy stored per frequency.");
+		for (freq, energy) in &energy_buffers {
+			out.sample(
+				"fedstorage_energy",
+				&[("frequency", &freq.0)],
+				energy.value(),
+			);
+		}
+		out.header(
+			"fedstorage_energy_capacity",
+			"gauge",
+			"Energy capacity per frequency.",
+		);
+		for (freq, _) in &energy_buffers {
+			let capacity = energy_capacity(&config, freq);
+			out.sample(
+				"fedstorage_energy_capacity",
+				&[("frequency", &freq.0)],
+				capacity,
+			);
+		}
+		out.header(
+			"fedstorage_energy_loss_total",
+			"counter",
+			"Energy lost on insertion per frequency.",
+		);
+		for (freq, energy) in &energy_buffers {
+			let loss = energy.loss();
+			out.sample(
+				"fedstorage_energy_loss_total",
+				&[("frequency", &freq.0)],
+				loss,
+			);
+		}
+		let clients = self
+			.clients
+			.read()
+			.await
+			.values()
+			.cloned()
+			.collect::<Vec<_>>();
+		out.header(
+			"fedstorage_connected_clients",
+			"gauge",
+			"Connected TCP clients.",
+		);
+		out.sample("fedstorage_connected_clients", &[], clients.len());
+		//セッションIDは接続ごとに変わるので、ホスト名ごとに最も遅いものをまとめる
+		let mut syncs = BTreeMap::<String, (i64, i64)>::new();
+		for meta in clients {
+			let meta = meta.lock().await;
+			let p95 = meta.sync_window.summary().p95;
+			let sync = syncs.entry(meta.hostname.clone()).or_default();
+			*sync = (sync.0.max(meta.last_sync_time), sync.1.max(p95));
+		}
+		out.header(
+			"fedstorage_client_last_sync_milliseconds",
+			"gauge",
+			"Slowest last sync duration of the clients per hostname.",
+		);
+		for (hostname, (sync, _)) in &syncs {
+			let labels = [("hostname", hostname.as_str())];
+			out.sample("fedstorage_client_last_sync_milliseconds", &labels, sync);
+		}
+		out.header(
+			"fedstorage_client_sync_p95_milliseconds",
+			"gauge",
+			"Slowest 95th percentile of recent sync durations per hostname.",
+		);
+		for (hostname, (_, p95)) in &syncs {
+			let labels = [("hostname", hostname.as_str())];
+			out.sample("fedstorage_client_sync_p95_milliseconds", &labels, p95);
+		}
+		let metrics = &self.metrics;
+		out.header(
+			"fedstorage_commands_total",
+			"counter",
+			"Client commands processed.",
+		);
+		for (command, count) in metrics.commands() {
+			if let Some(name) = crate::client::command_name(command) {
+				out.sample("fedstorage_commands_total", &[("command", name)], count);
+			}
+		}
+		let counters = [
+			(
+				"fedstorage_received_bytes_total",
+				"Bytes received from clients.",
+				metrics.bytes_in.load(Ordering::Relaxed),
+			),
+			(
+				"fedstorage_sent_bytes_total",
+				"Bytes sent to clients.",
+				metrics.bytes_out.load(Ordering::Relaxed),
+			),
+			(
+				"fedstorage_rejected_item_stacks_total",
+				"Item stacks rejected on insertion.",
+				metrics.rejected_item_stacks.load(Ordering::Relaxed),
+			),
+			(
+				"fedstorage_rejected_energy_total",
+				"Energy rejected on insertion.",
+				metrics.rejected_energy.load(Ordering::Relaxed),
+			),
+		];
+		for (name, help, value) in counters {
+			out.header(name, "counter", help);
+			out.sample(name, &[], value);
+		}
+		out.0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::{TcpListener, TcpStream},
+	};
+
+	use crate::{client::ClientSession, write_string, GlobalObject};
+
+	#[test]
+	fn render_metrics() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				go.metrics.command(2);
+				go.metrics.command(2);
+				go.metrics.rejected_energy(500);
+				let text = go.render_metrics().await;
+				let lines = text.lines().collect::<Vec<_>>();
+				assert!(lines.contains(&r#"fedstorage_item_stacks{frequency="RED, RED, RED"} 1"#));
+				assert!(lines.contains(
+					&r#"fedstorage_fluid_amount{frequency="RED, RED, RED",fluid="water"} 2147483747"#
+				));
+				assert!(lines
+					.contains(&r#"fedstorage_energy{frequency="WHITE, WHITE, WHITE"} 4294967795"#));
+				assert!(lines.contains(&r#"fedstorage_commands_total{command="ItemFromClient"} 2"#));
+				assert!(lines.contains(&"fedstorage_rejected_energy_total 500"));
+				assert!(lines.contains(&"fedstorage_connected_clients 0"));
+				assert_eq!(super::escape("a\"b\\c\n"), r#"a\"b\\c\n"#);
+			});
+	}
+	#[test]
+	fn client_series() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = Arc::new(GlobalObject::dummy().await);
+				let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+				let mut clients = Vec::new();
+				for _ in 0..2 {
+					let addr = listener.local_addr().unwrap();
+					let mut client = TcpStream::connect(addr).await.unwrap();
+					let (soc, addr) = listener.accept().await.unwrap();
+					let session = tokio::spawn(ClientSession::new(soc, addr, go.clone()).session());
+					client.read_i64().await.unwrap();
+					client.write_i8(8).await.unwrap();
+					write_string(&mut client, "survival").await.unwrap();
+					//ホスト名の設定には応答が無いので問い合わせで処理済みを待つ
+					client.write_i8(1).await.unwrap();
+					write_string(&mut client, "RED").await.unwrap();
+					client.write_i8(11).await.unwrap();
+					client.read_i64().await.unwrap();
+					clients.push((client, session));
+				}
+				let sync_series = |text: &str| {
+					text.lines()
+						.filter(|l| l.starts_with("fedstorage_client_last_sync_milliseconds{"))
+						.map(|l| l.to_owned())
+						.collect::<Vec<_>>()
+				};
+				//セッションごとではなくホスト名ごと
+				let text = go.render_metrics().await;
+				assert_eq!(
+					sync_series(&text),
+					[r#"fedstorage_client_last_sync_milliseconds{hostname="survival"} 0"#]
+				);
+				//切断したら消える
+				for (client, session) in clients {
+					drop(client);
+					let _ = session.await;
+				}
+				let text = go.render_metrics().await;
+				assert!(sync_series(&text).is_empty());
+				assert!(text.lines().any(|l| l == "fedstorage_connected_clients 0"));
+			});
+	}
+}
@@ -0,0 +1,343 @@
+use serde::Serialize;
+
+/// 入れ子の上限 Minecraftと同じ
+const MAX_DEPTH: usize = 512;
+
+/// MinecraftのNBT(バイナリタグ)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+	Byte(i8),
+	Short(i16),
+	Int(i32),
+	Long(i64),
+	Float(f32),
+	Double(f64),
+	ByteArray(Vec<i8>),
+	String(String),
+	List(Vec<Tag>),
+	/// 書き込み順を保持する
+	Compound(Vec<(String, Tag)>),
+	IntArray(Vec<i32>),
+	LongArray(Vec<i64>),
+}
+struct Reader<'a> {
+	data: &'a [u8],
+}
+impl<'a> Reader<'a> {
+	fn take(&mut self, len: usize) -> Result<&'a [u8], std::io::Error> {
+		if self.data.len() < len {
+			return Err(std::io::ErrorKind::UnexpectedEof.into());
+		}
+		let (head, tail) = self.data.split_at(len);
+		self.data = tail;
+		Ok(head)
+	}
+	fn array<const N: usize>(&mut self) -> Result<[u8; N], std::io::Error> {
+		Ok(self.take(N)?.try_into().unwrap())
+	}
+	fn u8(&mut self) -> Result<u8, std::io::Error> {
+		Ok(self.take(1)?[0])
+	}
+	fn len(&mut self) -> Result<usize, std::io::Error> {
+		let len = i32::from_be_bytes(self.array()?);
+		len.try_into().map_err(std::io::Error::other)
+	}
+	/// Javaの修正UTF-8だが、通常の文字列の範囲ではUTF-8と同じ
+	fn string(&mut self) -> Result<String, std::io::Error> {
+		let len = u16::from_be_bytes(self.array()?);
+		Ok(String::from_utf8_lossy(self.take(len.into())?).into_owned())
+	}
+	fn payload(&mut self, tag_type: u8, depth: usize) -> Result<Tag, std::io::Error> {
+		if depth > MAX_DEPTH {
+			return Err(std::io::Error::other("NBT too deep"));
+		}
+		Ok(match tag_type {
+			1 => Tag::Byte(self.u8()? as i8),
+			2 => Tag::Short(i16::from_be_bytes(self.array()?)),
+			3 => Tag::Int(i32::from_be_bytes(self.array()?)),
+			4 => Tag::Long(i64::from_be_bytes(self.array()?)),
+			5 => Tag::Float(f32::from_be_bytes(self.array()?)),
+			6 => Tag::Double(f64::from_be_bytes(self.array()?)),
+			7 => {
+				let len = self.len()?;
+				Tag::ByteArray(self.take(len)?.iter().map(|b| *b as i8).collect())
+			}
+			8 => Tag::String(self.string()?),
+			9 => {
+				let item_type = self.u8()?;
+				let len = self.len()?;
+				let mut list = Vec::new();
+				for _ in 0..len {
+					list.push(self.payload(item_type, depth + 1)?);
+				}
+				Tag::List(list)
+			}
+			10 => {
+				let mut compound = Vec::new();
+				loop {
+					let tag_type = self.u8()?;
+					if tag_type == 0 {
+						break;
+					}
+					let name = self.string()?;
+					compound.push((name, self.payload(tag_type, depth + 1)?));
+				}
+				Tag::Compound(compound)
+			}
+			11 => {
+				let len = self.len()?;
+				let data = self.take(len.saturating_mul(4))?;
+				let ints = data.chunks_exact(4);
+				Tag::IntArray(
+					ints.map(|b| i32::from_be_bytes(b.try_into().unwrap()))
+						.collect(),
+				)
+			}
+			12 => {
+				let len = self.len()?;
+				let data = self.take(len.saturating_mul(8))?;
+				let longs = data.chunks_exact(8);
+				Tag::LongArray(
+					longs
+						.map(|b| i64::from_be_bytes(b.try_into().unwrap()))
+						.collect(),
+				)
+			}
+			_ => {
+				return Err(std::io::Error::other(format!(
+					"Unknown NBT Tag {}",
+					tag_type
+				)))
+			}
+		})
+	}
+}
+impl Tag {
+	/// 名前付きのルートタグを読む
+	pub fn decode(data: &[u8]) -> Result<Self, std::io::Error> {
+		let mut r = Reader { data };
+		let tag_type = r.u8()?;
+		if tag_type == 0 {
+			return Ok(Tag::Compound(Vec::new()));
+		}
+		let _name = r.string()?;
+		r.payload(tag_type, 0)
+	}
+	pub fn get(&self, name: &str) -> Option<&Tag> {
+		match self {
+			Tag::Compound(compound) => compound.iter().find(|(k, _)| k == name).map(|(_, v)| v),
+			_ => None,
+		}
+	}
+	pub fn as_i64(&self) -> Option<i64> {
+		match self {
+			Tag::Byte(v) => Some(*v as i64),
+			Tag::Short(v) => Some(*v as i64),
+			Tag::Int(v) => Some(*v as i64),
+			Tag::Long(v) => Some(*v),
+			_ => None,
+		}
+	}
+	pub fn to_json(&self) -> serde_json::Value {
+		use serde_json::Value;
+		match self {
+			Tag::Byte(v) => Value::from(*v),
+			Tag::Short(v) => Value::from(*v),
+			Tag::Int(v) => Value::from(*v),
+			Tag::Long(v) => Value::from(*v),
+			Tag::Float(v) => Value::from(*v),
+			Tag::Double(v) => Value::from(*v),
+			Tag::ByteArray(v) => Value::from(v.clone()),
+			Tag::String(v) => Value::from(v.clone()),
+			Tag::List(v) => Value::Array(v.iter().map(Tag::to_json).collect()),
+			Tag::Compound(v) => {
+				Value::Object(v.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())
+			}
+			Tag::IntArray(v) => Value::from(v.clone()),
+			Tag::LongArray(v) => Value::from(v.clone()),
+		}
+	}
+	/// アイテムの表示名
+	pub fn display_name(&self) -> Option<String> {
+		let name = self.get("display")?.get("Name")?;
+		let Tag::String(name) = name else {
+			return None;
+		};
+		//1.13以降はJSONテキスト
+		match serde_json::from_str::<serde_json::Value>(name) {
+			Ok(serde_json::Value::Object(text)) => text
+				.get("text")
+				.and_then(|t| t.as_str())
+				.map(|t| t.to_owned()),
+			Ok(serde_json::Value::String(text)) => Some(text),
+			_ => Some(name.clone()),
+		}
+	}
+	/// 1.12以前の`ench`と1.13以降の`Enchantments`、本の`StoredEnchantments`
+	pub fn enchantments(&self) -> Vec<Enchantment> {
+		let mut enchantments = Vec::new();
+		for key in ["ench", "Enchantments", "StoredEnchantments"] {
+			let Some(Tag::List(list)) = self.get(key) else {
+				continue;
+			};
+			for ench in list {
+				let id = match ench.get("id") {
+					Some(Tag::String(id)) => id.clone(),
+					Some(id) => id.as_i64().map(|id| id.to_string()).unwrap_or_default(),
+					None => continue,
+				};
+				let level = ench.get("lvl").and_then(Tag::as_i64).unwrap_or(0);
+				enchantments.push(Enchantment { id, level });
+			}
+		}
+		enchantments
+	}
+}
+/// SNBT(文字列形式)で書く
+impl std::fmt::Display for Tag {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		fn join<T>(
+			f: &mut std::fmt::Formatter<'_>,
+			items: &[T],
+			mut item: impl FnMut(&mut std::fmt::Formatter<'_>, &T) -> std::fmt::Result,
+		) -> std::fmt::Result {
+			for (i, v) in items.iter().enumerate() {
+				if i > 0 {
+					write!(f, ",")?;
+				}
+				item(f, v)?;
+			}
+			Ok(())
+		}
+		/// SNBTに無限大とNaNの表記は無いので、無限大は最大値に、NaNは0にする
+		fn finite(v: f64, max: f64) -> f64 {
+			if v.is_nan() {
+				0.0
+			} else {
+				v.clamp(-max, max)
+			}
+		}
+		fn quote(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+			write!(f, "\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+		}
+		match self {
+			Tag::Byte(v) => write!(f, "{}b", v),
+			Tag::Short(v) => write!(f, "{}s", v),
+			Tag::Int(v) => write!(f, "{}", v),
+			Tag::Long(v) => write!(f, "{}L", v),
+			Tag::Float(v) => write!(f, "{:?}f", finite(*v as f64, f32::MAX as f64) as f32),
+			Tag::Double(v) => write!(f, "{:?}d", finite(*v, f64::MAX)),
+			Tag::ByteArray(v) => {
+				write!(f, "[B;")?;
+				join(f, v, |f, v| write!(f, "{}b", v))?;
+				write!(f, "]")
+			}
+			Tag::String(v) => quote(f, v),
+			Tag::List(v) => {
+				write!(f, "[")?;
+				join(f, v, |f, v| write!(f, "{}", v))?;
+				write!(f, "]")
+			}
+			Tag::Compound(v) => {
+				write!(f, "{{")?;
+				join(f, v, |f, (k, v)| {
+					let plain = !k.is_empty()
+						&& k.chars()
+							.all(|c| c.is_ascii_alphanumeric() || "_-.+".contains(c));
+					if plain {
+						write!(f, "{}", k)?;
+					} else {
+						quote(f, k)?;
+					}
+					write!(f, ":{}", v)
+				})?;
+				write!(f, "}}")
+			}
+			Tag::IntArray(v) => {
+				write!(f, "[I;")?;
+				join(f, v, |f, v| write!(f, "{}", v))?;
+				write!(f, "]")
+			}
+			Tag::LongArray(v) => {
+				write!(f, "[L;")?;
+				join(f, v, |f, v| write!(f, "{}L", v))?;
+				write!(f, "]")
+			}
+		}
+	}
+}
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Enchantment {
+	pub id: String,
+	pub level: i64,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Enchantment, Tag};
+
+	/// {display:{Name:"Foo"},ench:[{id:16s,lvl:5s}],nums:[I;1,2]}
+	fn sample() -> Vec<u8> {
+		let mut v = vec![10, 0, 0];
+		v.extend([10, 0, 7]);
+		v.extend(b"display");
+		v.extend([8, 0, 4]);
+		v.extend(b"Name");
+		v.extend([0, 3]);
+		v.extend(b"Foo");
+		v.push(0);
+		v.extend([9, 0, 4]);
+		v.extend(b"ench");
+		v.extend([10, 0, 0, 0, 1]);
+		v.extend([2, 0, 2]);
+		v.extend(b"id");
+		v.extend([0, 16]);
+		v.extend([2, 0, 3]);
+		v.extend(b"lvl");
+		v.extend([0, 5]);
+		v.push(0);
+		v.extend([11, 0, 4]);
+		v.extend(b"nums");
+		v.extend([0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2]);
+		v.push(0);
+		v
+	}
+	#[test]
+	fn decode() {
+		let tag = Tag::decode(&sample()).unwrap();
+		assert_eq!(tag.display_name(), Some("Foo".into()));
+		assert_eq!(
+			tag.enchantments(),
+			vec![Enchantment {
+				id: "16".into(),
+				level: 5
+			}]
+		);
+		assert_eq!(
+			tag.to_json(),
+			serde_json::json!({
+				"display": { "Name": "Foo" },
+				"ench": [{ "id": 16, "lvl": 5 }],
+				"nums": [1, 2]
+			})
+		);
+		assert_eq!(
+			tag.to_string(),
+			r#"{display:{Name:"Foo"},ench:[{id:16s,lvl:5s}],nums:[I;1,2]}"#
+		);
+		let quoted = Tag::Compound(vec![("a b".into(), Tag::String("\"\\".into()))]);
+		assert_eq!(quoted.to_string(), r#"{"a b":"\"\\"}"#);
+		let floats = Tag::List(vec![
+			Tag::Float(f32::INFINITY),
+			Tag::Float(f32::NAN),
+			Tag::Double(f64::NEG_INFINITY),
+			Tag::Double(1.5),
+		]);
+		assert_eq!(
+			floats.to_string(),
+			"[3.4028235e38f,0.0f,-1.7976931348623157e308d,1.5d]"
+		);
+		assert!(Tag::decode(&sample()[..10]).is_err());
+		assert!(Tag::decode(&[99, 0, 0]).is_err());
+	}
+}
@@ -0,0 +1,141 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+use crate::{
+	item::{GzipNBT, ItemStack, NBT},
+	nbt::{Enchantment, Tag},
+	GlobalObject,
+};
+
+/// gzip圧縮されたNBTのmd5
+pub type NbtHash = [u8; 16];
+
+/// 同じ内容のNBTを1つだけ保持する共有ストア
+///
+/// 参照数は`Arc`で数え、ストア以外から参照されなくなったものは`purge`で削除する
+#[derive(Debug, Default)]
+pub struct NbtStore(Mutex<HashMap<NbtHash, Arc<[u8]>>>);
+impl NbtStore {
+	fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<NbtHash, Arc<[u8]>>> {
+		self.0.lock().unwrap_or_else(|e| e.into_inner())
+	}
+	/// 既に同じ内容があればそれを共有する
+	///
+	/// ハッシュはサーバーで計算したもので、同じハッシュで内容が違えば衝突として拒否する
+	pub fn intern(&self, gz: GzipNBT) -> Result<GzipNBT, tokio::io::Error> {
+		let mut store = self.lock();
+		let data = store
+			.entry(*gz.hash())
+			.or_insert_with(|| gz.shared_data())
+			.clone();
+		if *data != *gz.as_gzip() {
+			return Err(tokio::io::Error::other("NBT Hash Collision"));
+		}
+		Ok(GzipNBT::from_shared(*gz.hash(), data))
+	}
+	pub fn get(&self, hash: &NbtHash) -> Option<GzipNBT> {
+		let data = self.lock().get(hash)?.clone();
+		Some(GzipNBT::from_shared(*hash, data))
+	}
+	/// どのスタックからも参照されていないNBTを削除し、削除した数を返す
+	pub fn purge(&self) -> usize {
+		let mut store = self.lock();
+		let before = store.len();
+		store.retain(|_, data| Arc::strong_count(data) > 1);
+		before - store.len()
+	}
+}
+pub fn hash(data: &[u8]) -> NbtHash {
+	use md5::Digest;
+	let mut hasher = md5::Md5::new();
+	hasher.update(data);
+	hasher.finalize().into()
+}
+/// 一覧に表示するNBTの情報
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NbtDisplay {
+	pub display_name: Option<String>,
+	pub enchantments: Vec<Enchantment>,
+}
+impl NbtDisplay {
+	pub fn new(tag: Option<&Tag>) -> Self {
+		Self {
+			display_name: tag.and_then(Tag::display_name),
+			enchantments: tag.map(Tag::enchantments).unwrap_or_default(),
+		}
+	}
+}
+/// gzipのNBTを一覧の取得のたびに展開しないよう、ハッシュごとに表示用の情報を覚えておく
+#[derive(Debug, Default)]
+pub struct DisplayCache(Mutex<HashMap<NbtHash, NbtDisplay>>);
+impl DisplayCache {
+	fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<NbtHash, NbtDisplay>> {
+		self.0.lock().unwrap_or_else(|e| e.into_inner())
+	}
+	pub fn get(&self, hash: &NbtHash) -> Option<NbtDisplay> {
+		self.lock().get(hash).cloned()
+	}
+	pub fn insert(&self, hash: NbtHash, display: NbtDisplay) {
+		self.lock().insert(hash, display);
+	}
+	/// 共有ストアから消えたNBTの分を削除する
+	pub fn purge(&self, store: &NbtStore) {
+		self.lock().retain(|hash, _| store.get(hash).is_some());
+	}
+}
+impl GlobalObject {
+	/// gzipのNBTは展開結果をハッシュごとに使い回す 解析できないNBTは空として扱う
+	pub(crate) async fn item_display(&self, item: &ItemStack) -> NbtDisplay {
+		let decode = || async {
+			let tag = match &item.nbt {
+				Some(nbt) => nbt.decode().await.ok().flatten(),
+				None => None,
+			};
+			NbtDisplay::new(tag.as_ref())
+		};
+		let Some(NBT::Extra(Some(gz))) = &item.nbt else {
+			return decode().await;
+		};
+		if let Some(display) = self.nbt_display.get(gz.hash()) {
+			return display;
+		}
+		let display = decode().await;
+		self.nbt_display.insert(*gz.hash(), display.clone());
+		display
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::NbtStore;
+	use crate::item::GzipNBT;
+
+	#[test]
+	fn intern() {
+		let store = NbtStore::default();
+		let a = b"nbt_store::tests::intern".to_vec();
+		let first = store.intern(GzipNBT::from_gzip(a.clone())).unwrap();
+		let second = store.intern(GzipNBT::from_gzip(a)).unwrap();
+		assert!(Arc::ptr_eq(&first.shared_data(), &second.shared_data()));
+		assert!(store.get(first.hash()).is_some());
+		let hash = *first.hash();
+		drop(first);
+		drop(second);
+		store.purge();
+		assert!(store.get(&hash).is_none());
+	}
+	#[test]
+	fn collision() {
+		let store = NbtStore::default();
+		let a = GzipNBT::from_gzip(b"a".to_vec());
+		let kept = store.intern(a.clone()).unwrap();
+		//ハッシュが同じでも内容が違えば共有しない
+		let forged = GzipNBT::from_shared(*a.hash(), b"b".to_vec().into());
+		assert!(store.intern(forged).is_err());
+		assert_eq!(store.get(a.hash()).unwrap().as_gzip(), kept.as_gzip());
+	}
+}
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{item::match_pattern, GlobalObject};
+
+/// 未指定時の最大件数
+const SEARCH_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchKind {
+	Item,
+	Fluid,
+}
+/// `q`に`*`を含めば`item_order`の優先指定と同じワイルドカード、含まなければ大文字小文字を区別しない部分一致
+///
+/// アイテムはIDの他に表示名も対象にする
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+	pub q: String,
+	/// 未指定はアイテムと液体の両方
+	pub kind: Option<SearchKind>,
+	pub limit: Option<usize>,
+}
+/// 周波数ごと・IDごとの合計
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SearchHit {
+	/// item/fluid
+	pub kind: &'static str,
+	pub frequency: String,
+	/// アイテムIDか液体名
+	pub id: String,
+	pub count: i64,
+	pub stacks: usize,
+}
+impl SearchQuery {
+	fn matches(&self, id: &str) -> bool {
+		if self.q.contains('*') {
+			match_pattern(&self.q, id)
+		} else {
+			id.to_lowercase().contains(&self.q.to_lowercase())
+		}
+	}
+	fn includes(&self, kind: SearchKind) -> bool {
+		self.kind.is_none_or(|k| k == kind)
+	}
+}
+impl GlobalObject {
+	/// IDごとに多い周波数から並べる
+	pub(crate) async fn search(&self, query: &SearchQuery) -> Vec<SearchHit> {
+		let mut totals = BTreeMap::<(&'static str, String, String), (i64, usize)>::new();
+		if query.includes(SearchKind::Item) {
+			for (freq, items) in self.item_buffers.snapshot() {
+				//表示名のためにNBTを展開する間は搬入出を止めない
+				let data = items.data.read().await.clone();
+				for is in data.iter() {
+					if !query.matches(&is.id) {
+						let display = self.item_display(is).await;
+						let name = display.display_name;
+						if !name.is_some_and(|name| query.matches(&name)) {
+							continue;
+						}
+					}
+					let key = ("item", is.id.clone(), freq.0.clone());
+					let total = totals.entry(key).or_default();
+					total.0 = total.0.saturating_add(is.count as i64);
+					total.1 += 1;
+				}
+			}
+		}
+		if query.includes(SearchKind::Fluid) {
+			for (freq, fluids) in self.fluid_buffers.snapshot() {
+				for fs in fluids.snapshot().await {
+					if query.matches(&fs.name) {
+						let key = ("fluid", fs.name, freq.0.clone());
+						let total = totals.entry(key).or_default();
+						total.0 = total.0.saturating_add(fs.count);
+						total.1 += 1;
+					}
+				}
+			}
+		}
+		let mut hits = totals
+			.into_iter()
+			.map(|((kind, id, frequency), (count, stacks))| SearchHit {
+				kind,
+				frequency,
+				id,
+				count,
+				stacks,
+			})
+			.collect::<Vec<_>>();
+		hits.sort_by(|a, b| {
+			(a.kind, &a.id)
+				.cmp(&(b.kind, &b.id))
+				.then(b.count.cmp(&a.count))
+		});
+		hits.truncate(query.limit.unwrap_or(SEARCH_LIMIT));
+		hits
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{SearchKind, SearchQuery};
+	use crate::{
+		fluid::FluidStack,
+		item::{ItemStack, NBT},
+		nbt_store::NbtDisplay,
+		Frequency, GlobalObject,
+	};
+
+	#[test]
+	fn search() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				go.items(&Frequency("BLUE".into()))
+					.insert_items(&mut vec![
+						ItemStack::dummy(),
+						ItemStack::dummy(),
+						ItemStack::dummy_with_id("minecraft:diamond"),
+					])
+					.await;
+				let query = |q: &str, kind| SearchQuery {
+					q: q.into(),
+					kind,
+					limit: None,
+				};
+				let hits = go.search(&query("STONE", None)).await;
+				let found = hits
+					.iter()
+					.map(|h| (h.frequency.as_str(), h.count, h.stacks))
+					.collect::<Vec<_>>();
+				//多い周波数から
+				assert_eq!(
+					found,
+					[
+						("BLUE", 128, 2),
+						("RED, RED, RED", 64, 1),
+						("WHITE, BLUE, WHITE", 64, 1)
+					]
+				);
+				let hits = go.search(&query("*:dia*", None)).await;
+				assert_eq!(hits.len(), 1);
+				assert_eq!(hits[0].id, "minecraft:diamond");
+				assert!(go.search(&query("dia", None)).await.len() == 1);
+				assert!(go.search(&query("*dia", None)).await.is_empty());
+				let hits = go.search(&query("water", Some(SearchKind::Fluid))).await;
+				assert_eq!(hits[0].kind, "fluid");
+				assert!(go
+					.search(&query("water", Some(SearchKind::Item)))
+					.await
+					.is_empty());
+			});
+	}
+	#[test]
+	fn search_display_name() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let freq = Frequency("WHITE, BLUE, WHITE".into());
+				let nbt = go.items(&freq).data.read().await[0].nbt.clone();
+				let Some(NBT::Extra(Some(gz))) = nbt else {
+					unreachable!()
+				};
+				//一覧で展開済みの表示名で探せる
+				let display = NbtDisplay {
+					display_name: Some("Excalibur".into()),
+					enchantments: Vec::new(),
+				};
+				go.nbt_display.insert(*gz.hash(), display);
+				let query = SearchQuery {
+					q: "excal".into(),
+					kind: None,
+					limit: None,
+				};
+				let hits = go.search(&query).await;
+				assert_eq!(hits.len(), 1);
+				assert_eq!(hits[0].frequency, freq.0);
+				assert_eq!(hits[0].id, "minecraft:stone");
+			});
+	}
+	#[test]
+	fn search_saturates() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let freq = Frequency("LAVA".into());
+				let fluids = go.fluids(&freq);
+				for nbt in [None, Some(vec![1])] {
+					let lava = FluidStack::new("lava".into(), i64::MAX, nbt);
+					fluids.insert_fluid(lava).await;
+				}
+				let query = SearchQuery {
+					q: "lava".into(),
+					kind: Some(SearchKind::Fluid),
+					limit: None,
+				};
+				//NBT違いの同じ液体の合計が溢れたら上限で止める
+				let hits = go.search(&query).await;
+				assert_eq!((hits[0].count, hits[0].stacks), (i64::MAX, 2));
+			});
+	}
+}
@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::GlobalObject;
+
+/// 全周波数の合計
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+	pub item_stacks: usize,
+	pub item_count: i64,
+	/// アイテムIDごとの数
+	pub items: BTreeMap<String, i64>,
+	/// 液体名ごとの量
+	pub fluids: BTreeMap<String, i64>,
+	pub energy: i64,
+	pub frequencies: FrequencyCount,
+	pub clients: usize,
+}
+/// 種類ごとの周波数の数
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct FrequencyCount {
+	pub item: usize,
+	pub fluid: usize,
+	pub energy: usize,
+}
+impl GlobalObject {
+	pub(crate) async fn summary(&self) -> Summary {
+		let mut summary = Summary::default();
+		let item_buffers = self.item_buffers.snapshot();
+		summary.frequencies.item = item_buffers.len();
+		for (_, items) in item_buffers {
+			let data = items.data.read().await;
+			summary.item_stacks += data.len();
+			for is in data.iter() {
+				let count = summary.items.entry(is.id.clone()).or_default();
+				*count = count.saturating_add(is.count as i64);
+			}
+		}
+		summary.item_count = summary
+			.items
+			.values()
+			.fold(0, |sum, c| sum.saturating_add(*c));
+		let fluid_buffers = self.fluid_buffers.snapshot();
+		summary.frequencies.fluid = fluid_buffers.len();
+		for (_, fluids) in fluid_buffers {
+			for fs in fluids.snapshot().await {
+				let amount = summary.fluids.entry(fs.name).or_default();
+				*amount = amount.saturating_add(fs.count);
+			}
+		}
+		let energy_buffers = self.energy_buffers.snapshot();
+		summary.frequencies.energy = energy_buffers.len();
+		//周波数ごとの量はi64に収まっても合計は溢れ得るので上限で止める
+		summary.energy = energy_buffers
+			.iter()
+			.fold(0i64, |sum, (_, e)| sum.saturating_add(e.value()));
+		summary.clients = self.clients.read().await.len();
+		summary
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{fluid::FluidStack, Frequency, GlobalObject};
+
+	#[test]
+	fn summary() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				let summary = go.summary().await;
+				assert_eq!(summary.item_stacks, 2);
+				assert_eq!(summary.item_count, 128);
+				assert_eq!(summary.items["minecraft:stone"], 128);
+				assert_eq!(summary.fluids["water"], i32::MAX as i64 + 100);
+				assert_eq!(summary.energy, u32::MAX as i64 + 500);
+				assert_eq!(summary.frequencies.item, 2);
+				assert_eq!(summary.frequencies.fluid, 1);
+				assert_eq!(summary.frequencies.energy, 1);
+				assert_eq!(summary.clients, 0);
+			});
+	}
+	#[test]
+	fn summary_saturates() {
+		tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap()
+			.block_on(async {
+				let go = GlobalObject::dummy().await;
+				for freq in ["A", "B"] {
+					let freq = Frequency(freq.into());
+					go.energy(&freq).merge(i64::MAX);
+					let lava = FluidStack::new("lava".into(), i64::MAX, None);
+					go.fluids(&freq).insert_fluid(lava).await;
+				}
+				//合計が溢れたら上限で止める
+				let summary = go.summary().await;
+				assert_eq!(summary.energy, i64::MAX);
+				assert_eq!(summary.fluids["lava"], i64::MAX);
+			});
+	}
+}